@@ -0,0 +1,57 @@
+//! Compares receiving 10 000 small messages as `Vec<u8>` copies and as `Bytes` slices.
+//! Run with `cargo bench --features bytes --bench bytes`.
+
+use futures_ringbuf::RingBuffer;
+use message_sink::MessageSink;
+use std::time::{Duration, Instant};
+
+const MESSAGES: usize = 10_000;
+const SIZE: usize = 32;
+
+/// A sink whose read buffer already holds every remaining message, so only parsing is
+/// timed
+async fn loaded() -> MessageSink<RingBuffer<u8>> {
+    let capacity = MESSAGES * (SIZE + 4);
+    let mut sink = MessageSink::new(RingBuffer::new(capacity));
+    sink.read_chunk_size(capacity);
+    sink.write_all(vec![vec![0; SIZE]; MESSAGES]).unwrap();
+    sink.recv().await.unwrap().unwrap();
+    sink
+}
+
+async fn vec() -> Duration {
+    let mut sink = loaded().await;
+    let start = Instant::now();
+    for _ in 1..MESSAGES {
+        sink.try_recv().unwrap().unwrap();
+    }
+    start.elapsed()
+}
+
+async fn bytes() -> Duration {
+    let mut sink = loaded().await;
+    let start = Instant::now();
+    for _ in 1..MESSAGES {
+        sink.try_recv_bytes().unwrap().unwrap();
+    }
+    start.elapsed()
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    const ITERATIONS: u32 = 20;
+    let mut vec_total = Duration::ZERO;
+    let mut bytes_total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        vec_total += vec().await;
+        bytes_total += bytes().await;
+    }
+    println!(
+        "bytes: 10 000 x 32 B as Vec: {:?}/iter",
+        vec_total / ITERATIONS
+    );
+    println!(
+        "bytes: 10 000 x 32 B as Bytes: {:?}/iter",
+        bytes_total / ITERATIONS
+    );
+}
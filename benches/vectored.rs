@@ -0,0 +1,63 @@
+//! Writes 16 MB messages to a stream that accepts vectored writes of any size, so the time
+//! is dominated by copying on the way into the outbound buffer.
+//! Run with `cargo bench --bench vectored`.
+
+use futures::io::{AsyncRead, AsyncWrite};
+use message_sink::MessageSink;
+use std::{
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+/// Discards everything written to it and never produces any bytes to read.
+struct Discard;
+
+impl AsyncRead for Discard {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for Discard {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(bufs.iter().map(|buf| buf.len()).sum()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    const ITERATIONS: u32 = 20;
+    let mut sink = MessageSink::new(Discard);
+    let mut elapsed = std::time::Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let message = vec![0; 16 * 1024 * 1024];
+        let start = Instant::now();
+        sink.write(message).unwrap();
+        sink.flush().await.unwrap();
+        elapsed += start.elapsed();
+    }
+    println!("vectored: 16 MB message: {:?}/iter", elapsed / ITERATIONS);
+}
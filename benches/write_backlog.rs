@@ -0,0 +1,58 @@
+//! Drains a 1 MB outbound backlog through a stream that accepts 1 KB per write.
+//! Run with `cargo bench --bench write_backlog`.
+
+use futures::io::{AsyncRead, AsyncWrite};
+use message_sink::MessageSink;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+/// Accepts at most `chunk` bytes per write and never produces any bytes to read.
+struct Trickle {
+    chunk: usize,
+}
+
+impl AsyncRead for Trickle {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for Trickle {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len().min(self.chunk)))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    const ITERATIONS: u32 = 20;
+    let message = vec![0; 1024 * 1024];
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut sink = MessageSink::new(Trickle { chunk: 1024 });
+        sink.write(message.clone()).unwrap();
+        sink.flush().await.unwrap();
+    }
+    println!(
+        "write_backlog: 1 MB in 1 KB writes: {:?}/iter",
+        start.elapsed() / ITERATIONS
+    );
+}
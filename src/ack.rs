@@ -0,0 +1,75 @@
+use crate::SinkError;
+use futures::{task::AtomicWaker, Future};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+const PENDING: u8 = 0;
+const WRITTEN: u8 = 1;
+const DROPPED: u8 = 2;
+
+struct State {
+    outcome: AtomicU8,
+    waker: AtomicWaker,
+}
+
+/// Resolves once a message queued with [`MessageSink::write_acked`](crate::MessageSink::write_acked)
+/// has been written to the stream in full. The ack only observes the sink, which still has
+/// to be polled or flushed to make progress. Resolves to `SinkError::Closed` if the message
+/// is dropped unwritten, e.g. because the sink failed, was reset or was dropped first.
+pub struct WriteAck {
+    state: Arc<State>,
+}
+
+/// The writer's end of a `WriteAck`, failing it if dropped before the message is written
+pub(crate) struct Acker {
+    state: Arc<State>,
+}
+
+pub(crate) fn ack() -> (Acker, WriteAck) {
+    let state = Arc::new(State {
+        outcome: AtomicU8::new(PENDING),
+        waker: AtomicWaker::new(),
+    });
+    let acker = Acker {
+        state: state.clone(),
+    };
+    (acker, WriteAck { state })
+}
+
+impl Acker {
+    pub(crate) fn written(self) {
+        self.state.outcome.store(WRITTEN, Ordering::Release);
+        self.state.waker.wake();
+    }
+}
+
+impl Drop for Acker {
+    fn drop(&mut self) {
+        let _ = self.state.outcome.compare_exchange(
+            PENDING,
+            DROPPED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        self.state.waker.wake();
+    }
+}
+
+impl Future for WriteAck {
+    type Output = Result<(), SinkError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Register first so an ack completed in between is not missed
+        self.state.waker.register(cx.waker());
+        match self.state.outcome.load(Ordering::Acquire) {
+            PENDING => Poll::Pending,
+            WRITTEN => Poll::Ready(Ok(())),
+            _ => Poll::Ready(Err(SinkError::Closed)),
+        }
+    }
+}
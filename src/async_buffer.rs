@@ -1,23 +1,144 @@
 use std::{
-    ops::Range,
+    collections::VecDeque,
+    io::IoSlice,
     task::{Context, Waker},
 };
 
+/// Small writes are copied onto the tail segment until it reaches this size, larger ones are
+/// queued as segments of their own so they are never copied.
+const COALESCE: usize = 4 * 1024;
+
+/// Most slices handed to a single vectored write.
+const MAX_SLICES: usize = 64;
+
+/// Outbound bytes are queued as a list of segments so that large messages can be written
+/// straight out of the `Vec` they were handed to us in.
+/// Bytes consumed from the front segment are tracked with an offset rather than shifted out
+/// on every write. Once the consumed prefix outgrows the live bytes we compact, so each byte
+/// is moved at most a constant number of times.
 #[derive(Default)]
 pub struct AsyncBuffer {
-    buffer: Vec<u8>,
+    segments: VecDeque<Vec<u8>>,
+    offset: usize,
+    len: usize,
     waker: Option<Waker>,
 }
 
 impl AsyncBuffer {
-    pub fn as_ref(&mut self) -> &Vec<u8> {
-        &self.buffer
+    /// An empty buffer that queues small writes into `vec`'s allocation. Like `consume`,
+    /// only keeps allocations no larger than a segment, a bigger one would sit empty at the
+    /// front once a large write is queued behind it.
+    pub fn recycle(mut vec: Vec<u8>) -> Self {
+        let mut buffer = Self::default();
+        if vec.capacity() <= COALESCE {
+            vec.clear();
+            buffer.segments.push_back(vec);
+        }
+        buffer
+    }
+    /// The unconsumed bytes, reusing the front segment's allocation where possible
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let Some(mut front) = self.segments.pop_front() else {
+            return Vec::new();
+        };
+        front.drain(0..self.offset);
+        for segment in self.segments {
+            front.extend(segment);
+        }
+        front
     }
-    pub fn drain(&mut self, range: Range<usize>) {
-        self.buffer.drain(range);
+    /// The unconsumed bytes of the front segment
+    pub fn as_ref(&self) -> &[u8] {
+        match self.segments.front() {
+            Some(segment) => &segment[self.offset..],
+            None => &[],
+        }
+    }
+    /// The unconsumed bytes of the first few segments, in order
+    pub fn io_slices(&self) -> Vec<IoSlice<'_>> {
+        self.segments
+            .iter()
+            .take(MAX_SLICES)
+            .enumerate()
+            .map(|(i, segment)| match i {
+                0 => IoSlice::new(self.as_ref()),
+                _ => IoSlice::new(segment),
+            })
+            .collect()
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Mark the first `length` unconsumed bytes as written
+    pub fn consume(&mut self, mut length: usize) {
+        self.len -= length;
+        while let Some(front) = self.segments.front_mut() {
+            let small = front.capacity() <= COALESCE;
+            let remaining = front.len() - self.offset;
+            if length < remaining {
+                self.offset += length;
+                if self.offset >= remaining - length {
+                    front.drain(0..self.offset);
+                    self.offset = 0;
+                }
+                return;
+            }
+            length -= remaining;
+            self.offset = 0;
+            // Keep the last small segment's allocation around for the next write
+            if small && self.segments.len() == 1 {
+                self.segments[0].clear();
+                return;
+            }
+            self.segments.pop_front();
+        }
+    }
+    /// Drop every unconsumed byte, keeping a small front segment's allocation for reuse
+    pub fn clear(&mut self) {
+        self.segments.truncate(1);
+        match self.segments.front_mut() {
+            Some(front) if front.capacity() <= COALESCE => front.clear(),
+            _ => self.segments.clear(),
+        }
+        self.offset = 0;
+        self.len = 0;
+    }
+    /// Make room for `additional` bytes to be coalesced onto the tail without reallocating.
+    /// Never reserves more than a segment's worth, larger writes get segments of their own.
+    pub fn reserve(&mut self, additional: usize) {
+        let additional = additional.min(COALESCE);
+        match self.segments.back_mut() {
+            Some(back) if back.len() + additional <= COALESCE => back.reserve(additional),
+            _ => self.segments.push_back(Vec::with_capacity(additional)),
+        }
     }
     pub fn extend(&mut self, vec: Vec<u8>) {
-        self.buffer.extend(vec);
+        if vec.is_empty() {
+            return;
+        }
+        self.len += vec.len();
+        match self.segments.back_mut() {
+            // A reserved segment too small for `vec` is swapped out rather than grown
+            Some(back) if back.is_empty() && vec.len() > back.capacity() => *back = vec,
+            Some(back) if back.len() + vec.len() <= COALESCE => back.extend(vec),
+            _ => self.segments.push_back(vec),
+        }
+        self.wake();
+    }
+    /// Like `extend`, but copies `slice` in rather than taking ownership of a `Vec`
+    pub fn extend_from_slice(&mut self, slice: &[u8]) {
+        if slice.is_empty() {
+            return;
+        }
+        self.len += slice.len();
+        match self.segments.back_mut() {
+            Some(back) if back.len() + slice.len() <= COALESCE => back.extend_from_slice(slice),
+            Some(back) if back.is_empty() => back.extend_from_slice(slice),
+            _ => self.segments.push_back(slice.to_vec()),
+        }
         self.wake();
     }
     pub fn wake(&mut self) {
@@ -30,3 +151,115 @@ impl AsyncBuffer {
         self.waker = Some(cx.waker().clone());
     }
 }
+
+#[cfg(test)]
+mod async_buffer_test {
+    use super::*;
+
+    #[test]
+    fn consume() {
+        let mut buffer = AsyncBuffer::default();
+        buffer.extend((0..=255).collect());
+        buffer.consume(16);
+        assert_eq!(buffer.len(), 240);
+        assert_eq!(buffer.as_ref()[0], 16);
+        buffer.consume(200);
+        assert_eq!(buffer.len(), 40);
+        assert_eq!(buffer.as_ref()[0], 216);
+        buffer.extend(vec![0; 8]);
+        assert_eq!(buffer.len(), 48);
+        buffer.consume(48);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn extend() {
+        let mut buffer = AsyncBuffer::default();
+        buffer.extend(vec![1, 2, 3]);
+        buffer.extend(vec![4, 5]);
+        let buffer = &buffer;
+        assert_eq!(buffer.as_ref(), &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn segments() {
+        let mut buffer = AsyncBuffer::default();
+        buffer.extend(vec![1; 4]);
+        buffer.extend(vec![2; COALESCE]);
+        buffer.extend(vec![3; 4]);
+        let lengths: Vec<usize> = buffer.io_slices().iter().map(|s| s.len()).collect();
+        assert_eq!(lengths, vec![4, COALESCE, 4]);
+        buffer.consume(6);
+        assert_eq!(buffer.len(), COALESCE + 2);
+        assert_eq!(buffer.as_ref()[0], 2);
+        let lengths: Vec<usize> = buffer.io_slices().iter().map(|s| s.len()).collect();
+        assert_eq!(lengths, vec![COALESCE - 2, 4]);
+    }
+
+    #[test]
+    fn recycle() {
+        let mut buffer = AsyncBuffer::recycle(Vec::with_capacity(1024));
+        buffer.extend(vec![1; 100]);
+        buffer.consume(100);
+        buffer.extend(vec![2; 10]);
+        assert_eq!(buffer.segments.len(), 1);
+        let vec = buffer.into_vec();
+        assert_eq!(vec, vec![2; 10]);
+        assert_eq!(vec.capacity(), 1024);
+        // Larger allocations are dropped rather than left empty ahead of a large write
+        let mut buffer = AsyncBuffer::recycle(Vec::with_capacity(COALESCE * 4));
+        buffer.extend(vec![3; COALESCE * 2]);
+        let lengths: Vec<usize> = buffer.io_slices().iter().map(|s| s.len()).collect();
+        assert_eq!(lengths, vec![COALESCE * 2]);
+    }
+
+    #[test]
+    fn extend_from_slice() {
+        let mut buffer = AsyncBuffer::default();
+        buffer.extend_from_slice(&[1, 2, 3]);
+        buffer.extend(vec![4, 5]);
+        buffer.extend_from_slice(&[6; COALESCE]);
+        assert_eq!(buffer.len(), COALESCE + 5);
+        assert_eq!(buffer.as_ref(), &[1, 2, 3, 4, 5]);
+        assert_eq!(buffer.segments.len(), 2);
+    }
+
+    #[test]
+    fn clear() {
+        let mut buffer = AsyncBuffer::default();
+        buffer.extend(vec![1; 100]);
+        buffer.extend(vec![2; COALESCE * 2]);
+        buffer.consume(10);
+        buffer.clear();
+        assert!(buffer.is_empty());
+        assert!(buffer.as_ref().is_empty());
+        assert_eq!(buffer.segments.len(), 1);
+        buffer.extend(vec![3; 10]);
+        assert_eq!(buffer.as_ref(), &[3; 10]);
+        // A large message's allocation isn't held on to
+        buffer.clear();
+        buffer.extend(vec![4; COALESCE * 4]);
+        buffer.clear();
+        assert!(buffer.segments.is_empty());
+        buffer.extend(vec![5; 10]);
+        assert_eq!(buffer.as_ref(), &[5; 10]);
+    }
+
+    #[test]
+    fn reserve() {
+        let mut buffer = AsyncBuffer::default();
+        buffer.reserve(100);
+        assert!(buffer.segments.back().unwrap().capacity() >= 100);
+        buffer.extend(vec![1; 60]);
+        buffer.reserve(1000);
+        assert!(buffer.segments.back().unwrap().capacity() >= 1060);
+        buffer.reserve(COALESCE * 4);
+        assert_eq!(buffer.segments.len(), 2);
+        assert!(buffer.segments.back().unwrap().capacity() >= COALESCE);
+        buffer.extend(vec![2; COALESCE * 2]);
+        assert_eq!(buffer.segments.len(), 2);
+        assert_eq!(buffer.len(), 60 + COALESCE * 2);
+        buffer.consume(60 + COALESCE * 2);
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{
+    reader::FrameReader, timer::IdleTimer, writer::FrameWriter, Endianness, HeaderWidth,
+    LengthSemantics, Magic, MessageSink, Timer,
+};
+use futures::io::{AsyncRead, AsyncWrite};
+use std::time::Duration;
+
+/// Collects a sink's configuration up front so that it is in place before the first poll.
+#[derive(Default)]
+pub struct MessageSinkBuilder {
+    reader: FrameReader,
+    writer: FrameWriter,
+}
+
+impl MessageSinkBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// See [`MessageSink::limit`]
+    pub fn limit(mut self, length: usize) -> Self {
+        self.reader.limit = length;
+        self
+    }
+    /// See [`MessageSink::read_chunk_size`]
+    pub fn read_chunk_size(mut self, bytes: usize) -> Self {
+        self.reader.chunk = bytes;
+        self
+    }
+    /// See [`MessageSink::max_pending_frames`]
+    pub fn max_pending_frames(mut self, n: usize) -> Self {
+        self.reader.max_pending = n.max(1);
+        self
+    }
+    /// See [`MessageSink::shrink_threshold`]
+    pub fn shrink_threshold(mut self, multiple: Option<usize>) -> Self {
+        self.reader.shrink = multiple;
+        self
+    }
+    /// See [`MessageSink::read_timeout`]
+    pub fn read_timeout(mut self, duration: Duration, timer: impl Timer + 'static) -> Self {
+        self.reader.timeout = Some(IdleTimer::new(duration, timer));
+        self
+    }
+    /// See [`MessageSink::channels`]
+    pub fn channels(mut self, enabled: bool) -> Self {
+        self.reader.format.channels = enabled;
+        self.writer.format.channels = enabled;
+        self
+    }
+    /// See [`MessageSink::keepalive`]
+    pub fn keepalive(mut self, interval: Duration, timer: impl Timer + 'static) -> Self {
+        self.reader.format.tagged = true;
+        self.writer.format.tagged = true;
+        self.writer.keepalive = Some(IdleTimer::new(interval, timer));
+        self
+    }
+    /// See [`MessageSink::high_water_mark`]
+    pub fn high_water_mark(mut self, bytes: usize) -> Self {
+        self.writer.high_water = bytes;
+        self
+    }
+    /// See [`MessageSink::coalesce`]
+    pub fn coalesce(mut self, bytes: Option<usize>) -> Self {
+        self.writer.coalesce = bytes;
+        self
+    }
+    /// See [`MessageSink::write_limit`]
+    pub fn write_limit(mut self, bytes: usize) -> Self {
+        self.writer.limit = bytes;
+        self
+    }
+    /// See [`MessageSink::header_width`]
+    pub fn header_width(mut self, width: HeaderWidth) -> Self {
+        self.reader.format.width = width;
+        self.writer.format.width = width;
+        self
+    }
+    /// See [`MessageSink::endianness`]
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.reader.format.endianness = endianness;
+        self.writer.format.endianness = endianness;
+        self
+    }
+    /// See [`MessageSink::length_semantics`]
+    pub fn length_semantics(mut self, length: LengthSemantics) -> Self {
+        self.reader.format.length = length;
+        self.writer.format.length = length;
+        self
+    }
+    /// See [`MessageSink::tokio_compat`]
+    pub fn tokio_compat(self) -> Self {
+        self.header_width(HeaderWidth::U32)
+            .endianness(Endianness::Big)
+    }
+    /// See [`MessageSink::magic`]
+    pub fn magic(mut self, prefix: &[u8], version: u8) -> Self {
+        let magic = Magic {
+            prefix: prefix.to_vec(),
+            version,
+        };
+        self.reader.format.magic = Some(magic.clone());
+        self.writer.format.magic = Some(magic);
+        self
+    }
+    /// See [`MessageSink::checksum`]
+    pub fn checksum(mut self, enabled: bool) -> Self {
+        self.reader.format.checksum = enabled;
+        self.writer.format.checksum = enabled;
+        self
+    }
+    pub fn build<S>(self, stream: S) -> MessageSink<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        MessageSink::from_parts(stream, self.reader, self.writer)
+    }
+}
+
+#[cfg(test)]
+mod builder_test {
+    use super::*;
+    use futures_ringbuf::RingBuffer;
+
+    #[tokio::test]
+    async fn build() {
+        let mut sink = MessageSinkBuilder::new()
+            .limit(300)
+            .read_chunk_size(16)
+            .header_width(HeaderWidth::U16)
+            .endianness(Endianness::Big)
+            .build(RingBuffer::new(1024));
+        assert_eq!(sink.reader.limit, 300);
+        assert_eq!(sink.reader.chunk, 16);
+        let message = vec![7; 200];
+        sink.write(message.clone()).unwrap();
+        assert_eq!(&sink.writer.buffer.as_ref()[..2], &[0, 200]);
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+    }
+}
@@ -0,0 +1,296 @@
+use crate::frame::{Frame, FrameFormat, FrameKind, ParseError, Span};
+
+/// The header, body and trailer of a frame, in the order they are sent
+pub type FrameParts = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+/// How messages are laid out on the wire. A [`MessageSink`](crate::MessageSink) handles the
+/// buffering, backpressure and bookkeeping, the codec only frames and finds messages.
+/// [`FrameFormat`], the length-prefixed framing, is the default.
+///
+/// Decoding works on a borrowed slice of the read buffer rather than draining it, so that
+/// every complete frame in one read can be located before any bytes are moved.
+pub trait Codec: Unpin {
+    /// The bytes written ahead of `message`
+    fn header(&self, message: &[u8]) -> Result<Vec<u8>, ParseError>;
+    /// The bytes written after `message`, none by default
+    fn trailer(&self, _message: &[u8]) -> Vec<u8> {
+        Vec::new()
+    }
+    /// Like `header`, for a message sent on `channel`. Codecs without channels reject any
+    /// channel other than 0 as `ParseError::Corrupt`.
+    fn channel_header(&self, channel: u16, message: &[u8]) -> Result<Vec<u8>, ParseError> {
+        match channel {
+            0 => self.header(message),
+            _ => Err(ParseError::Corrupt),
+        }
+    }
+    /// Find the frame at the start of `buffer`, failing with `ParseError::NotReady` if it is
+    /// incomplete and `ParseError::TooLarge` if it declares a message longer than `limit`,
+    /// or `ParseError::LimitExceeded` if its message outgrows `limit` without declaring a
+    /// length.
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Span, ParseError>;
+    /// Number of bytes that must be read before `buffer` could hold a complete frame, or 0 if
+    /// it already does (or is corrupt). Defaults to a byte at a time.
+    fn needed(&self, buffer: &[u8], limit: usize) -> usize {
+        match self.decode(buffer, limit) {
+            Err(ParseError::NotReady) => 1,
+            _ => 0,
+        }
+    }
+    /// A frame the peer decodes as control and discards, sent as a keepalive. Codecs without
+    /// control frames return `None`, the default.
+    fn ping(&self) -> Option<Vec<u8>> {
+        None
+    }
+    /// The header, body and trailer to send for `message` on `channel`. The body is the
+    /// message itself unless the codec transforms it, e.g. by compressing it.
+    fn frame(&self, channel: u16, message: Vec<u8>) -> Result<FrameParts, ParseError> {
+        let header = self.channel_header(channel, &message)?;
+        let trailer = self.trailer(&message);
+        Ok((header, message, trailer))
+    }
+    /// Append `message` to `out`, framed
+    fn encode(&self, message: &[u8], out: &mut Vec<u8>) -> Result<(), ParseError> {
+        out.extend(self.header(message)?);
+        out.extend(message);
+        out.extend(self.trailer(message));
+        Ok(())
+    }
+}
+
+impl Codec for FrameFormat {
+    fn header(&self, message: &[u8]) -> Result<Vec<u8>, ParseError> {
+        self.channel_header(0, message)
+    }
+    fn trailer(&self, message: &[u8]) -> Vec<u8> {
+        self.encode_trailer(message)
+    }
+    fn channel_header(&self, channel: u16, message: &[u8]) -> Result<Vec<u8>, ParseError> {
+        self.encode_header(FrameKind::Message, channel, message.len())
+    }
+    #[cfg(feature = "compression")]
+    fn frame(&self, channel: u16, message: Vec<u8>) -> Result<FrameParts, ParseError> {
+        let compressed = match (self.tagged, &self.compression) {
+            (true, Some(compression)) => compression.compress(&message),
+            _ => None,
+        };
+        let Some(body) = compressed else {
+            let header = self.channel_header(channel, &message)?;
+            let trailer = self.trailer(&message);
+            return Ok((header, message, trailer));
+        };
+        let header = self.encode_compressed_header(channel, body.len())?;
+        let trailer = self.trailer(&body);
+        Ok((header, body, trailer))
+    }
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Span, ParseError> {
+        Frame::locate(buffer, self, limit)
+    }
+    fn needed(&self, buffer: &[u8], limit: usize) -> usize {
+        Frame::needed(buffer, self, limit)
+    }
+    fn ping(&self) -> Option<Vec<u8>> {
+        match self.tagged {
+            true => self.encode_header(FrameKind::Ping, 0, 0).ok(),
+            false => None,
+        }
+    }
+}
+
+/// Messages separated by a delimiter byte with no length prefix, e.g. `b'\n'` for
+/// line-oriented protocols. Messages containing the delimiter are rejected as
+/// `ParseError::Corrupt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DelimiterCodec {
+    pub delim: u8,
+}
+
+impl DelimiterCodec {
+    pub fn new(delim: u8) -> Self {
+        Self { delim }
+    }
+}
+
+impl Codec for DelimiterCodec {
+    fn header(&self, message: &[u8]) -> Result<Vec<u8>, ParseError> {
+        match message.contains(&self.delim) {
+            true => Err(ParseError::Corrupt),
+            false => Ok(Vec::new()),
+        }
+    }
+    fn trailer(&self, _message: &[u8]) -> Vec<u8> {
+        vec![self.delim]
+    }
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Span, ParseError> {
+        match buffer.iter().position(|byte| *byte == self.delim) {
+            Some(end) if end > limit => Err(ParseError::LimitExceeded),
+            Some(end) => Ok(Span::message(0..end, end + 1)),
+            // Without a delimiter in sight the message is at least as long as the buffer
+            None if buffer.len() > limit => Err(ParseError::LimitExceeded),
+            None => Err(ParseError::NotReady),
+        }
+    }
+}
+
+/// Records of exactly `size` bytes with no header at all, e.g. raw telemetry. Messages of
+/// any other length, and a `size` of 0, are rejected as `ParseError::Corrupt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedCodec {
+    pub size: usize,
+}
+
+impl FixedCodec {
+    pub fn new(size: usize) -> Self {
+        Self { size }
+    }
+}
+
+impl Codec for FixedCodec {
+    fn header(&self, message: &[u8]) -> Result<Vec<u8>, ParseError> {
+        match self.size > 0 && message.len() == self.size {
+            true => Ok(Vec::new()),
+            false => Err(ParseError::Corrupt),
+        }
+    }
+    fn decode(&self, buffer: &[u8], limit: usize) -> Result<Span, ParseError> {
+        match self.size {
+            0 => Err(ParseError::Corrupt),
+            size if size > limit => Err(ParseError::TooLarge {
+                declared: size as u64,
+                limit,
+            }),
+            size if size > buffer.len() => Err(ParseError::NotReady),
+            size => Ok(Span::message(0..size, size)),
+        }
+    }
+    fn needed(&self, buffer: &[u8], _limit: usize) -> usize {
+        self.size.saturating_sub(buffer.len())
+    }
+}
+
+#[cfg(test)]
+mod codec_test {
+    use super::*;
+    use crate::MessageSink;
+    use futures::FutureExt;
+    use futures_ringbuf::RingBuffer;
+
+    #[tokio::test]
+    async fn custom_codec() {
+        let lines = DelimiterCodec::new(b'\n');
+        let mut sink = MessageSink::with_codec(RingBuffer::new(1024), lines);
+        sink.write(b"hello".to_vec()).unwrap();
+        sink.write_all(vec![Vec::new(), b"world".to_vec()]).unwrap();
+        assert!(sink.write(b"two\nlines".to_vec()).is_err());
+        assert_eq!(Some(b"hello".to_vec()), sink.recv().await.unwrap());
+        assert_eq!(Some(Vec::new()), sink.recv().await.unwrap());
+        assert_eq!(Some(b"world".to_vec()), sink.recv().await.unwrap());
+        let (mut stream, _) = sink.into_inner();
+        futures::AsyncWriteExt::write_all(&mut stream, b"raw line\npartial")
+            .await
+            .unwrap();
+        let mut sink = MessageSink::with_codec(stream, lines);
+        assert_eq!(Some(b"raw line".to_vec()), sink.recv().await.unwrap());
+        assert!(sink.try_recv().unwrap().is_none());
+        assert_eq!(sink.into_inner().1, b"partial");
+    }
+
+    #[tokio::test]
+    async fn delimiter_one_read() {
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::write_all(&mut stream, b"one\ntwo\n\nthree")
+            .await
+            .unwrap();
+        let mut sink = MessageSink::with_codec(stream, DelimiterCodec::new(b'\n'));
+        assert_eq!(Some(b"one".to_vec()), sink.recv().await.unwrap());
+        assert_eq!(sink.reader.ready.len(), 2);
+        assert_eq!(Some(b"two".to_vec()), sink.try_recv().unwrap());
+        assert_eq!(Some(Vec::new()), sink.try_recv().unwrap());
+        assert_eq!(None, sink.try_recv().unwrap());
+        futures::AsyncWriteExt::write_all(sink.get_mut(), b"\n")
+            .await
+            .unwrap();
+        assert_eq!(Some(b"three".to_vec()), sink.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn delimiter_split_reads() {
+        let codec = DelimiterCodec::new(0);
+        let mut sink = MessageSink::with_codec(RingBuffer::new(1024), codec);
+        let mut encoded = Vec::new();
+        for message in [b"first".as_slice(), b"second"] {
+            codec.encode(message, &mut encoded).unwrap();
+        }
+        let mut received = Vec::new();
+        for chunk in encoded.chunks(4) {
+            futures::AsyncWriteExt::write_all(sink.get_mut(), chunk)
+                .await
+                .unwrap();
+            if let Some(message) = (&mut sink).now_or_never() {
+                received.push(message.unwrap().unwrap());
+            }
+        }
+        assert_eq!(received, vec![b"first".to_vec(), b"second".to_vec()]);
+        sink.write(b"round trip".to_vec()).unwrap();
+        assert_eq!(Some(b"round trip".to_vec()), sink.recv().await.unwrap());
+        assert!(sink.write(vec![1, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn delimiter_limit() {
+        let codec = DelimiterCodec::new(b'\n');
+        assert_eq!(codec.decode(b"abc\n", 3).unwrap().body(), 0..3);
+        assert_eq!(codec.decode(b"abcd\n", 3), Err(ParseError::LimitExceeded));
+        assert_eq!(codec.decode(b"abc", 3), Err(ParseError::NotReady));
+        assert_eq!(codec.decode(b"abcd", 3), Err(ParseError::LimitExceeded));
+    }
+
+    #[tokio::test]
+    async fn fixed() {
+        let records: Vec<u8> = (0..20).collect();
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::write_all(&mut stream, &records)
+            .await
+            .unwrap();
+        let mut sink = MessageSink::with_codec(stream, FixedCodec::new(8));
+        assert_eq!(Some(records[0..8].to_vec()), sink.recv().await.unwrap());
+        assert_eq!(Some(records[8..16].to_vec()), sink.recv().await.unwrap());
+        assert!((&mut sink).now_or_never().is_none());
+        assert_eq!(sink.reader.buffer.len(), 4);
+        sink.write(vec![7; 8]).unwrap();
+        assert!(sink.write(vec![7; 9]).is_err());
+        let mut expected = records[16..].to_vec();
+        expected.extend([7; 4]);
+        assert_eq!(Some(expected), sink.recv().await.unwrap());
+        assert_eq!(sink.into_inner().1, vec![7; 4]);
+    }
+
+    #[test]
+    fn fixed_decode() {
+        let codec = FixedCodec::new(4);
+        assert_eq!(codec.decode(&[1, 2, 3], 4), Err(ParseError::NotReady));
+        assert_eq!(codec.needed(&[1, 2, 3], 4), 1);
+        assert_eq!(codec.decode(&[1, 2, 3, 4, 5], 4).unwrap().total(), 4);
+        assert_eq!(
+            codec.decode(&[1, 2, 3, 4], 3),
+            Err(ParseError::TooLarge {
+                declared: 4,
+                limit: 3
+            })
+        );
+        assert_eq!(FixedCodec::new(0).decode(&[1], 4), Err(ParseError::Corrupt));
+    }
+
+    #[test]
+    fn frame_format() {
+        let format = FrameFormat::default();
+        let mut out = Vec::new();
+        Codec::encode(&format, b"abc", &mut out).unwrap();
+        assert_eq!(out, vec![3, 0, 0, 0, b'a', b'b', b'c']);
+        let span = format.decode(&out, usize::MAX).unwrap();
+        assert_eq!(span.body(), 4..7);
+        assert_eq!(span.total(), 7);
+        assert_eq!(format.needed(&out[..2], usize::MAX), 2);
+        assert!(format.ping().is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use crate::frame::ParseError;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use std::io::{Read, Write};
+
+/// Compress message bodies with deflate before framing them. Messages shorter than
+/// `threshold`, and those that don't shrink, are sent as they are. Compressed frames are
+/// marked in the kind byte, so the format must be tagged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub threshold: usize,
+    /// From 0 (fastest) to 9 (smallest)
+    pub level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            threshold: 256,
+            level: 6,
+        }
+    }
+}
+
+impl Compression {
+    /// `message` deflated, if it is long enough to be worth it and got shorter
+    pub(crate) fn compress(&self, message: &[u8]) -> Option<Vec<u8>> {
+        if message.len() < self.threshold {
+            return None;
+        }
+        let level = flate2::Compression::new(self.level.min(9));
+        let mut encoder = DeflateEncoder::new(Vec::new(), level);
+        encoder.write_all(message).ok()?;
+        let compressed = encoder.finish().ok()?;
+        (compressed.len() < message.len()).then_some(compressed)
+    }
+}
+
+/// Inflate a compressed body, failing with `ParseError::LimitExceeded` rather than
+/// inflating past `limit` bytes. The declared length only bounds the compressed size, so
+/// this is what stops a small frame from expanding without bound.
+pub(crate) fn decompress(body: &[u8], limit: usize) -> Result<Vec<u8>, ParseError> {
+    let mut message = Vec::new();
+    let cap = (limit as u64).saturating_add(1);
+    DeflateDecoder::new(body)
+        .take(cap)
+        .read_to_end(&mut message)
+        .map_err(|_| ParseError::Corrupt)?;
+    if message.len() > limit {
+        return Err(ParseError::LimitExceeded);
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod compression_test {
+    use super::*;
+    use crate::{codec::Codec, FrameFormat, HeaderWidth, MessageSink, SinkError};
+    use futures_ringbuf::RingBuffer;
+    use rand::RngCore;
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    fn compressible(len: usize) -> Vec<u8> {
+        br#"{"sensor":"north","value":21.5},"#.iter().copied().cycle().take(len).collect()
+    }
+
+    fn format() -> FrameFormat {
+        FrameFormat {
+            tagged: true,
+            compression: Some(Compression::default()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let messages = vec![compressible(4096), random(4096), compressible(16)];
+        let mut sink = MessageSink::new(RingBuffer::new(16 * 1024));
+        sink.compression(Some(Compression::default()));
+        sink.write_all(messages.clone()).unwrap();
+        sink.flush().await.unwrap();
+        let written = sink.stats().bytes_written as usize;
+        assert!(written < 4096 * 2);
+        for message in messages {
+            assert_eq!(Some(message), sink.recv().await.unwrap());
+        }
+    }
+
+    #[test]
+    fn flag() {
+        let format = format();
+        let message = compressible(1024);
+        let (header, body, trailer) = format.frame(0, message.clone()).unwrap();
+        assert!(body.len() < message.len());
+        assert_eq!(header[4] & 0x80, 0x80);
+        let mut framed = [header.clone(), body.clone(), trailer].concat();
+        let (frame, _) = crate::Frame::parse(&framed, &format, usize::MAX).unwrap();
+        assert_eq!(frame.into_message(), message);
+        // Without the flag the compressed bytes are the message
+        framed[4] = 0;
+        let (frame, _) = crate::Frame::parse(&framed, &format, usize::MAX).unwrap();
+        assert_eq!(frame.into_message(), body);
+        let (header, body, _) = format.frame(0, random(1024)).unwrap();
+        assert_eq!(header[4], 0);
+        assert_eq!(body.len(), 1024);
+        let (header, _, _) = format.frame(0, compressible(100)).unwrap();
+        assert_eq!(header[4], 0);
+    }
+
+    #[tokio::test]
+    async fn bomb() {
+        let bomb = vec![0; 16 * 1024 * 1024];
+        let mut framed = Vec::new();
+        let format = format();
+        let (header, body, trailer) = format.frame(0, bomb).unwrap();
+        assert!(body.len() < 64 * 1024);
+        framed.extend([header, body, trailer].concat());
+        let mut buffer = framed.clone();
+        assert_eq!(
+            crate::Frame::try_from(&mut buffer, &format, 64 * 1024).unwrap_err(),
+            ParseError::LimitExceeded
+        );
+        assert_eq!(buffer, framed);
+        let mut stream = RingBuffer::new(framed.len());
+        futures::AsyncWriteExt::write_all(&mut stream, &framed)
+            .await
+            .unwrap();
+        let mut sink = MessageSink::new(stream);
+        sink.compression(Some(Compression::default()));
+        sink.limit(64 * 1024);
+        match sink.recv().await {
+            Err(SinkError::LimitExceeded) => {}
+            result => panic!("unexpected result {:?}", result.map(|m| m.map(|m| m.len()))),
+        }
+        assert!(sink.reader.ready.is_empty());
+    }
+
+    #[tokio::test]
+    async fn take_read_buffer() {
+        let messages = vec![compressible(1000), compressible(1000)];
+        let format = FrameFormat {
+            width: HeaderWidth::U8,
+            ..format()
+        };
+        let mut framed = Vec::new();
+        for message in &messages {
+            let (header, body, trailer) = format.frame(0, message.clone()).unwrap();
+            framed.extend([header, body, trailer].concat());
+        }
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.header_width(HeaderWidth::U8);
+        sink.compression(Some(Compression::default()));
+        sink.write_raw(framed.clone()).unwrap();
+        assert_eq!(Some(messages[0].clone()), sink.recv().await.unwrap());
+        // The second message was inflated past what a U8 header can describe
+        assert_eq!(sink.reader.ready.len(), 1);
+        let second = framed.len() / 2;
+        assert_eq!(sink.take_read_buffer(), framed[second..]);
+    }
+
+    #[test]
+    fn decompress_limit() {
+        let compressed = Compression::default()
+            .compress(&compressible(4096))
+            .unwrap();
+        assert_eq!(decompress(&compressed, 4096).unwrap(), compressible(4096));
+        assert_eq!(
+            decompress(&compressed, 4095),
+            Err(ParseError::LimitExceeded)
+        );
+        assert_eq!(decompress(&[0xFF; 8], 4096), Err(ParseError::Corrupt));
+    }
+}
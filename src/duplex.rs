@@ -0,0 +1,101 @@
+use futures::io::{AsyncRead, AsyncWrite, IoSlice};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Joins a read half and a write half of different types into one stream, for transports
+/// that were split before they reached the sink, see
+/// [`MessageSink::from_halves`](crate::MessageSink::from_halves). The sink's second type
+/// parameter is its codec, so the halves share one stream type rather than taking a
+/// parameter each.
+pub struct Duplex<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Duplex<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+    pub fn get_ref(&self) -> (&R, &W) {
+        (&self.reader, &self.writer)
+    }
+    pub fn get_mut(&mut self) -> (&mut R, &mut W) {
+        (&mut self.reader, &mut self.writer)
+    }
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Duplex<R, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().reader).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Duplex<R, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().writer).poll_write(cx, buf)
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().writer).poll_write_vectored(cx, bufs)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod duplex_test {
+    use crate::{Codec, DelimiterCodec, FrameFormat, MessageSink};
+    use futures::io::Cursor;
+
+    #[tokio::test]
+    async fn from_halves() {
+        let format = FrameFormat::default();
+        let mut inbound = Vec::new();
+        format.encode(b"one", &mut inbound).unwrap();
+        format.encode(b"two", &mut inbound).unwrap();
+        let mut sink = MessageSink::from_halves(Cursor::new(inbound), Vec::new());
+        assert_eq!(Some(b"one".to_vec()), sink.recv().await.unwrap());
+        sink.write(b"three".to_vec()).unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(Some(b"two".to_vec()), sink.recv().await.unwrap());
+        let (duplex, _) = sink.into_inner();
+        let (_, outbound) = duplex.into_inner();
+        let mut expected = Vec::new();
+        format.encode(b"three", &mut expected).unwrap();
+        assert_eq!(outbound, expected);
+    }
+
+    #[tokio::test]
+    async fn from_halves_with_codec() {
+        let lines = DelimiterCodec::new(b'\n');
+        let inbound = Cursor::new(b"one\ntwo\n".to_vec());
+        let mut sink = MessageSink::from_halves_with_codec(inbound, Vec::new(), lines);
+        assert_eq!(Some(b"one".to_vec()), sink.recv().await.unwrap());
+        sink.write(b"three".to_vec()).unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(Some(b"two".to_vec()), sink.recv().await.unwrap());
+        let (duplex, _) = sink.into_inner();
+        assert_eq!(duplex.into_inner().1, b"three\n");
+    }
+}
@@ -0,0 +1,72 @@
+use crate::{MessageSink, SinkError};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    SinkExt,
+};
+
+/// Pump messages from `from` to `to` until `from` reaches EOF, then close `to`. Resolves to
+/// the number of messages forwarded. The first error on either side is returned after
+/// attempting to close `to`.
+pub async fn forward<R, W>(
+    from: &mut MessageSink<R>,
+    to: &mut MessageSink<W>,
+) -> Result<u64, SinkError>
+where
+    R: AsyncRead + AsyncWrite + Unpin,
+    W: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut count = 0;
+    let result = loop {
+        let message = match from.recv().await {
+            Ok(Some(message)) => message,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        if let Err(e) = to.send(message).await {
+            break Err(e);
+        }
+        count += 1;
+    };
+    let closed = SinkExt::close(to).await;
+    result.and(closed).map(|_| count)
+}
+
+#[cfg(test)]
+mod forward_test {
+    use super::*;
+    use futures_ringbuf::RingBuffer;
+
+    #[tokio::test]
+    async fn forward() {
+        let messages = [vec![1; 16], Vec::new(), vec![3; 300]];
+        let mut from = MessageSink::new(RingBuffer::new(1024));
+        from.write_all(messages.to_vec()).unwrap();
+        from.flush().await.unwrap();
+        futures::AsyncWriteExt::close(from.get_mut()).await.unwrap();
+        let mut to = MessageSink::new(RingBuffer::new(1024));
+        assert_eq!(super::forward(&mut from, &mut to).await.unwrap(), 3);
+        assert!(to.is_closed());
+        let (stream, _) = to.into_inner();
+        let mut to = MessageSink::new(stream);
+        for message in messages {
+            assert_eq!(Some(message), to.recv().await.unwrap());
+        }
+        assert_eq!(None, to.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn forward_error() {
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::write_all(&mut stream, &[8, 0, 0, 0, 1])
+            .await
+            .unwrap();
+        futures::AsyncWriteExt::close(&mut stream).await.unwrap();
+        let mut from = MessageSink::new(stream);
+        let mut to = MessageSink::new(RingBuffer::new(1024));
+        match super::forward(&mut from, &mut to).await {
+            Err(SinkError::UnexpectedEof) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(to.is_closed());
+    }
+}
@@ -1,12 +1,504 @@
-use std::fmt::Display;
+use crate::checksum::crc32;
+#[cfg(feature = "compression")]
+use crate::compression::{self, Compression};
+use std::{error::Error, fmt::Display, ops::Range};
+
+/// Part of a message delivered by `MessageSink::recv_chunk`. Messages too large to buffer
+/// whole arrive as several chunks, the last of which has `is_last` set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub data: Vec<u8>,
+    pub is_last: bool,
+}
 
 #[derive(Debug)]
-pub struct Frame(Vec<u8>);
+pub struct Frame {
+    message: Vec<u8>,
+    channel: u16,
+}
+
+/// Encoding of the length prefix of each frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderWidth {
+    U8,
+    U16,
+    #[default]
+    U32,
+    U64,
+    /// LEB128, using one byte for every 7 bits of the length
+    Varint,
+    /// A u32 packing a length of up to `0xFFFFFF` into its low 24 bits and a type into its
+    /// high 8 bits. The type is the frame's channel, so channels 0 to 255 can be told
+    /// apart without a field of their own.
+    Packed,
+}
 
+impl HeaderWidth {
+    /// Number of bytes in the length prefix, or `None` if it varies with the length
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            Self::U8 => Some(1),
+            Self::U16 => Some(2),
+            Self::U32 => Some(4),
+            Self::U64 => Some(8),
+            Self::Varint => None,
+            Self::Packed => Some(4),
+        }
+    }
+    fn max(&self) -> u64 {
+        if *self == Self::Packed {
+            return 0xFF_FFFF;
+        }
+        match self.size() {
+            Some(width) if width < 8 => (1 << (width * 8)) - 1,
+            _ => u64::MAX,
+        }
+    }
+}
+
+fn decode_varint(header: &[u8]) -> Result<(u64, usize), ParseError> {
+    let mut value: u64 = 0;
+    for (i, byte) in header.iter().enumerate() {
+        let bits = (*byte & 0x7F) as u64;
+        // The tenth byte may only carry the single remaining bit of a u64
+        if i == 9 && bits > 1 || i > 9 {
+            return Err(ParseError::Corrupt);
+        }
+        value |= bits << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(ParseError::NotReady)
+}
+
+fn encode_varint(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Number of bytes `encode_varint` uses for `value`
+fn varint_size(value: u64) -> usize {
+    (64 - value.leading_zeros() as usize).max(1).div_ceil(7)
+}
+
+/// Number of bytes a message of `payload_len` bytes occupies once framed with the default
+/// format
+pub fn framed_len(payload_len: usize) -> usize {
+    FrameFormat::default().framed_len(payload_len)
+}
+
+/// Iterate the frames at the start of `buffer` in the default format, see
+/// [`FrameFormat::frames`]
+pub fn frames(buffer: &mut Vec<u8>) -> FrameIter<'_> {
+    FrameFormat::default().frames(buffer)
+}
+
+/// Frames parsed off the front of a buffer, created by [`frames`]. Yields each complete
+/// frame, removing it from the buffer, and ends when the rest of the buffer is an
+/// incomplete frame or after yielding an error.
 #[derive(Debug)]
+pub struct FrameIter<'a> {
+    buffer: &'a mut Vec<u8>,
+    format: FrameFormat,
+    done: bool,
+}
+
+impl Iterator for FrameIter<'_> {
+    type Item = Result<Frame, ParseError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match Frame::try_from(self.buffer, &self.format, usize::MAX) {
+            Ok(frame) => Some(Ok(frame)),
+            Err(ParseError::NotReady) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Byte order of the length prefix of each frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+/// What the length prefix of each frame counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LengthSemantics {
+    /// The payload alone
+    #[default]
+    PayloadOnly,
+    /// The length prefix itself along with the payload
+    Inclusive,
+}
+
+/// Fixed bytes and a protocol version written at the start of every frame, so streams that
+/// aren't speaking the expected protocol are rejected early
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magic {
+    pub prefix: Vec<u8>,
+    pub version: u8,
+}
+
+/// Wire layout of the header written before each message
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FrameFormat {
+    pub magic: Option<Magic>,
+    pub width: HeaderWidth,
+    pub endianness: Endianness,
+    pub length: LengthSemantics,
+    /// Append a CRC32 of the message after each frame and verify it when parsing
+    pub checksum: bool,
+    /// Write a kind byte after the length of each frame so that control frames, such as
+    /// keepalive pings, can be told apart from messages
+    pub tagged: bool,
+    /// Write a channel id after the length of each frame so that several logical streams
+    /// can share one connection
+    pub channels: bool,
+    /// Deflate large message bodies, flagging them in the kind byte. Takes effect only when
+    /// the format is tagged. Compressed frames are inflated on arrival either way.
+    #[cfg(feature = "compression")]
+    pub compression: Option<Compression>,
+}
+
+/// Set in the kind byte of frames whose body is compressed
+const COMPRESSED: u8 = 0x80;
+
+/// What a frame carries, written after the length when the format is tagged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameKind {
+    Message = 0,
+    Ping = 1,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = ParseError;
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(FrameKind::Message),
+            1 => Ok(FrameKind::Ping),
+            _ => Err(ParseError::Corrupt),
+        }
+    }
+}
+
+/// Where a complete frame sits at the start of a buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub(crate) kind: FrameKind,
+    pub(crate) channel: u16,
+    pub(crate) body: Range<usize>,
+    pub(crate) total: usize,
+    /// The body has to be inflated to get the message
+    pub(crate) compressed: bool,
+}
+
+impl Span {
+    /// A frame `total` bytes long carrying the message at `body`
+    pub fn message(body: Range<usize>, total: usize) -> Self {
+        Self {
+            kind: FrameKind::Message,
+            channel: 0,
+            body,
+            total,
+            compressed: false,
+        }
+    }
+    /// A control frame `total` bytes long, consumed without being yielded
+    pub fn control(total: usize) -> Self {
+        Self {
+            kind: FrameKind::Ping,
+            channel: 0,
+            body: 0..0,
+            total,
+            compressed: false,
+        }
+    }
+    /// Where the message sits within the frame
+    pub fn body(&self) -> Range<usize> {
+        self.body.clone()
+    }
+    /// Number of bytes the whole frame occupies
+    pub fn total(&self) -> usize {
+        self.total
+    }
+    /// Copy the message out of `frame`, the buffer this span was located in, inflating it
+    /// if it is compressed
+    pub(crate) fn extract(&self, frame: &[u8], limit: usize) -> Result<Vec<u8>, ParseError> {
+        let body = &frame[self.body.clone()];
+        #[cfg(feature = "compression")]
+        if self.compressed {
+            return compression::decompress(body, limit);
+        }
+        let _ = limit;
+        Ok(body.to_vec())
+    }
+}
+
+impl FrameFormat {
+    /// The framing of `tokio_util::codec::LengthDelimitedCodec`'s defaults: a big-endian u32
+    /// length that counts only the payload
+    pub fn tokio_compat() -> Self {
+        Self {
+            width: HeaderWidth::U32,
+            endianness: Endianness::Big,
+            ..Default::default()
+        }
+    }
+    /// Iterate the frames at the start of `buffer`, leaving any trailing partial frame
+    /// buffered
+    pub fn frames<'a>(&self, buffer: &'a mut Vec<u8>) -> FrameIter<'a> {
+        FrameIter {
+            buffer,
+            format: self.clone(),
+            done: false,
+        }
+    }
+    /// Number of bytes a message of `payload_len` bytes occupies once framed
+    pub fn framed_len(&self, payload_len: usize) -> usize {
+        let width = self.length_width(payload_len as u64);
+        self.magic_size() + width + self.fields_size() + payload_len + self.trailer_size()
+    }
+    /// Number of bytes preceding the length prefix
+    fn magic_size(&self) -> usize {
+        self.magic
+            .as_ref()
+            .map_or(0, |magic| magic.prefix.len() + 1)
+    }
+    /// Check as much of the magic prefix and version as has been buffered
+    fn check_magic(&self, buffer: &[u8]) -> Result<(), ParseError> {
+        let Some(magic) = &self.magic else {
+            return Ok(());
+        };
+        let available = buffer.len().min(magic.prefix.len());
+        if buffer[..available] != magic.prefix[..available] {
+            return Err(ParseError::Corrupt);
+        }
+        match buffer.get(magic.prefix.len()) {
+            Some(version) if *version != magic.version => Err(ParseError::Corrupt),
+            _ => Ok(()),
+        }
+    }
+    /// Number of bytes in the length prefix of a frame carrying `size` bytes of payload
+    fn length_width(&self, size: u64) -> usize {
+        let Some(width) = self.width.size() else {
+            let mut width = varint_size(size);
+            // An inclusive varint may need another byte to count its own bytes
+            while self.length == LengthSemantics::Inclusive
+                && varint_size(size.saturating_add(width as u64)) > width
+            {
+                width += 1;
+            }
+            return width;
+        };
+        width
+    }
+    /// Decode the length prefix at the start of `header`, returning the payload length along
+    /// with the number of bytes the prefix occupied
+    fn decode_length(&self, header: &[u8]) -> Result<(u64, usize), ParseError> {
+        let (length, width) = self.decode_prefix(header)?;
+        match self.length {
+            LengthSemantics::PayloadOnly => Ok((length, width)),
+            LengthSemantics::Inclusive => length
+                .checked_sub(width as u64)
+                .map(|size| (size, width))
+                .ok_or(ParseError::Corrupt),
+        }
+    }
+    fn decode_prefix(&self, header: &[u8]) -> Result<(u64, usize), ParseError> {
+        let Some(width) = self.width.size() else {
+            return decode_varint(header);
+        };
+        if header.len() < width {
+            return Err(ParseError::NotReady);
+        }
+        let mut bytes = [0; 8];
+        let mut size = match self.endianness {
+            Endianness::Little => {
+                bytes[..width].copy_from_slice(&header[..width]);
+                u64::from_le_bytes(bytes)
+            }
+            Endianness::Big => {
+                bytes[8 - width..].copy_from_slice(&header[..width]);
+                u64::from_be_bytes(bytes)
+            }
+        };
+        if self.width == HeaderWidth::Packed {
+            size &= HeaderWidth::Packed.max();
+        }
+        Ok((size, width))
+    }
+    /// Index of the type byte in a packed length prefix
+    fn packed_type_index(&self) -> usize {
+        match self.endianness {
+            Endianness::Little => 3,
+            Endianness::Big => 0,
+        }
+    }
+    /// Whether frames carry a channel, either in a field of its own or packed with the
+    /// length
+    fn has_channel(&self) -> bool {
+        self.channels || self.width == HeaderWidth::Packed
+    }
+    /// Whether frames carry a 2 byte channel field after the length
+    fn channel_field(&self) -> bool {
+        self.channels && self.width != HeaderWidth::Packed
+    }
+    /// Number of bytes of optional fields between the length prefix and the body
+    fn fields_size(&self) -> usize {
+        2 * usize::from(self.channel_field()) + usize::from(self.tagged)
+    }
+    fn trailer_size(&self) -> usize {
+        if self.checksum {
+            4
+        } else {
+            0
+        }
+    }
+    fn decode_channel(&self, fields: &[u8]) -> u16 {
+        let bytes = [fields[0], fields[1]];
+        match self.endianness {
+            Endianness::Little => u16::from_le_bytes(bytes),
+            Endianness::Big => u16::from_be_bytes(bytes),
+        }
+    }
+    fn decode_checksum(&self, trailer: &[u8]) -> u32 {
+        let bytes = [trailer[0], trailer[1], trailer[2], trailer[3]];
+        match self.endianness {
+            Endianness::Little => u32::from_le_bytes(bytes),
+            Endianness::Big => u32::from_be_bytes(bytes),
+        }
+    }
+    fn encode_checksum(&self, checksum: u32) -> [u8; 4] {
+        match self.endianness {
+            Endianness::Little => checksum.to_le_bytes(),
+            Endianness::Big => checksum.to_be_bytes(),
+        }
+    }
+    /// Append `body` to `out` framed according to this format
+    pub(crate) fn encode_into(
+        &self,
+        channel: u16,
+        body: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), ParseError> {
+        out.extend(self.encode_header(FrameKind::Message, channel, body.len())?);
+        out.extend(body);
+        out.extend(self.encode_trailer(body));
+        Ok(())
+    }
+    /// The bytes that precede a body of `size` bytes: the magic prefix, if any, the length,
+    /// the channel if enabled and the kind if tagged. Fails with `ParseError::Corrupt` if a
+    /// channel other than 0 is given without channels enabled.
+    pub(crate) fn encode_header(
+        &self,
+        kind: FrameKind,
+        channel: u16,
+        size: usize,
+    ) -> Result<Vec<u8>, ParseError> {
+        self.encode_tagged_header(kind as u8, channel, size)
+    }
+    /// Like `encode_header`, for a message whose `size` byte body is compressed
+    #[cfg(feature = "compression")]
+    pub(crate) fn encode_compressed_header(
+        &self,
+        channel: u16,
+        size: usize,
+    ) -> Result<Vec<u8>, ParseError> {
+        self.encode_tagged_header(FrameKind::Message as u8 | COMPRESSED, channel, size)
+    }
+    /// Like `encode_header`, with the raw kind byte including any flags
+    fn encode_tagged_header(
+        &self,
+        tag: u8,
+        channel: u16,
+        size: usize,
+    ) -> Result<Vec<u8>, ParseError> {
+        if channel != 0 && !self.has_channel() {
+            return Err(ParseError::Corrupt);
+        }
+        let mut header = Vec::with_capacity(self.framed_len(size) - size - self.trailer_size());
+        if let Some(magic) = &self.magic {
+            header.extend(&magic.prefix);
+            header.push(magic.version);
+        }
+        let mut length = self.encode_length(size)?;
+        if self.width == HeaderWidth::Packed {
+            length[self.packed_type_index()] =
+                u8::try_from(channel).map_err(|_| ParseError::Corrupt)?;
+        }
+        header.extend(length);
+        if self.channel_field() {
+            header.extend(match self.endianness {
+                Endianness::Little => channel.to_le_bytes(),
+                Endianness::Big => channel.to_be_bytes(),
+            });
+        }
+        if self.tagged {
+            header.push(tag);
+        }
+        Ok(header)
+    }
+    /// The bytes that follow `body`: its checksum, if enabled
+    pub(crate) fn encode_trailer(&self, body: &[u8]) -> Vec<u8> {
+        match self.checksum {
+            true => self.encode_checksum(crc32(body)).to_vec(),
+            false => Vec::new(),
+        }
+    }
+    fn encode_length(&self, size: usize) -> Result<Vec<u8>, ParseError> {
+        let size: u64 = size.try_into().map_err(|_| ParseError::Corrupt)?;
+        let size = match self.length {
+            LengthSemantics::PayloadOnly => size,
+            LengthSemantics::Inclusive => size
+                .checked_add(self.length_width(size) as u64)
+                .ok_or(ParseError::Corrupt)?,
+        };
+        if size > self.width.max() {
+            return Err(ParseError::Corrupt);
+        }
+        let Some(width) = self.width.size() else {
+            return Ok(encode_varint(size));
+        };
+        Ok(match self.endianness {
+            Endianness::Little => size.to_le_bytes()[..width].to_vec(),
+            Endianness::Big => size.to_be_bytes()[8 - width..].to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ParseError {
     NotReady,
+    /// The header could not be parsed
     Corrupt,
+    /// A message without a declared length, e.g. one waiting on a delimiter, grew past
+    /// the limit
+    LimitExceeded,
+    /// The header is well formed but declares a message longer than the limit
+    TooLarge {
+        declared: u64,
+        limit: usize,
+    },
+    ChecksumMismatch,
 }
 
 impl Display for ParseError {
@@ -14,41 +506,183 @@ impl Display for ParseError {
         match self {
             Self::NotReady => write!(f, "Not ready"),
             Self::Corrupt => write!(f, "Corrupt"),
+            Self::LimitExceeded => write!(f, "Limit exceeded"),
+            Self::TooLarge { declared, limit } => {
+                write!(f, "Too large: {} bytes declared, limit {}", declared, limit)
+            }
+            Self::ChecksumMismatch => write!(f, "Checksum mismatch"),
         }
     }
 }
 
+impl Error for ParseError {}
+
 impl Frame {
     pub fn new(message: Vec<u8>) -> Self {
-        Self(message)
+        Self::with_channel(0, message)
+    }
+    /// A frame sent on `channel`, for formats with channels enabled
+    pub fn with_channel(channel: u16, message: Vec<u8>) -> Self {
+        Self { message, channel }
+    }
+    pub fn channel(&self) -> u16 {
+        self.channel
     }
     pub fn into_message(self) -> Vec<u8> {
-        self.0
+        self.message
     }
     /// Attempt to parse a message from a buffer, removing the bytes read if we successfully
     /// parse the message (or if the buffer is corrupt)
     /// Note: We do not implement TryFrom for this because that trait takes ownership of the
     /// vector. We want to re-use the same vector across multiple invocations of this
     /// function.
-    pub fn try_from(buffer: &mut Vec<u8>) -> std::result::Result<Frame, ParseError> {
-        if buffer.len() < 4 {
+    /// Frames declaring a message larger than `limit` are rejected as soon as the header
+    /// is available, before the body is buffered.
+    pub fn try_from(
+        buffer: &mut Vec<u8>,
+        format: &FrameFormat,
+        limit: usize,
+    ) -> std::result::Result<Frame, ParseError> {
+        let (frame, consumed) = Frame::parse(buffer, format, limit)?;
+        buffer.drain(0..consumed);
+        Ok(frame)
+    }
+    /// Parse a message from the start of `input` without modifying it, returning the frame
+    /// along with the number of bytes it occupied so the caller can advance their own
+    /// buffer. Control frames before the message are skipped and counted as consumed.
+    pub fn parse(
+        input: &[u8],
+        format: &FrameFormat,
+        limit: usize,
+    ) -> std::result::Result<(Frame, usize), ParseError> {
+        let mut offset = 0;
+        loop {
+            let frame = &input[offset..];
+            let span = Frame::locate(frame, format, limit)?;
+            offset += span.total;
+            // Control frames are consumed without being surfaced
+            if span.kind == FrameKind::Message {
+                let message = span.extract(frame, limit)?;
+                return Ok((Frame::with_channel(span.channel, message), offset));
+            }
+        }
+    }
+    /// Check whether `buffer` holds a complete frame without consuming it, returning the
+    /// range of its body, as sent if it is compressed. Fails the same way `try_from` would.
+    pub fn peek(
+        buffer: &[u8],
+        format: &FrameFormat,
+        limit: usize,
+    ) -> std::result::Result<Range<usize>, ParseError> {
+        let mut offset = 0;
+        loop {
+            let span = Frame::locate(&buffer[offset..], format, limit)?;
+            if span.kind == FrameKind::Message {
+                return Ok(offset + span.body.start..offset + span.body.end);
+            }
+            offset += span.total;
+        }
+    }
+    /// Find the frame at the start of `buffer`, whatever its kind, without consuming it
+    pub(crate) fn locate(
+        buffer: &[u8],
+        format: &FrameFormat,
+        limit: usize,
+    ) -> std::result::Result<Span, ParseError> {
+        let span = Frame::locate_header(buffer, format, limit)?;
+        if span.total > buffer.len() {
+            return Err(ParseError::NotReady);
+        }
+        let body = span.body.clone();
+        if format.checksum
+            && crc32(&buffer[body.clone()]) != format.decode_checksum(&buffer[body.end..])
+        {
+            return Err(ParseError::ChecksumMismatch);
+        }
+        Ok(span)
+    }
+    /// Like `locate`, but only needs the header to be buffered. The span describes where
+    /// the body and the end of the frame will be once the rest arrives.
+    pub(crate) fn locate_header(
+        buffer: &[u8],
+        format: &FrameFormat,
+        limit: usize,
+    ) -> std::result::Result<Span, ParseError> {
+        format.check_magic(buffer)?;
+        let offset = format.magic_size();
+        if buffer.len() < offset {
             return Err(ParseError::NotReady);
         }
-        let mut header: [u8; 4] = Default::default();
-        header[0] = buffer[0];
-        header[1] = buffer[1];
-        header[2] = buffer[2];
-        header[3] = buffer[3];
-        let size: usize = u32::from_le_bytes(header)
-            .try_into()
-            .map_err(|_| ParseError::Corrupt)?;
-        if size + 4 > buffer.len() {
+        let (declared, width) = format.decode_length(&buffer[offset..])?;
+        let size = match usize::try_from(declared) {
+            Ok(size) if size <= limit => size,
+            _ => return Err(ParseError::TooLarge { declared, limit }),
+        };
+        let header = offset + width + format.fields_size();
+        let total = size
+            .checked_add(header + format.trailer_size())
+            .ok_or(ParseError::Corrupt)?;
+        if header > buffer.len() {
             return Err(ParseError::NotReady);
         }
-        buffer.drain(0..4);
-        let mut message = Vec::new();
-        message.extend(buffer.drain(0..size));
-        Ok(Frame(message))
+        let fields = &buffer[offset + width..header];
+        let (channel, fields) = match (format.width, format.channels) {
+            (HeaderWidth::Packed, _) => {
+                let prefix = &buffer[offset..offset + width];
+                (u16::from(prefix[format.packed_type_index()]), fields)
+            }
+            (_, true) => (format.decode_channel(fields), &fields[2..]),
+            (_, false) => (0, fields),
+        };
+        let (kind, compressed) = match format.tagged {
+            true => (
+                FrameKind::try_from(fields[0] & !COMPRESSED)?,
+                fields[0] & COMPRESSED != 0,
+            ),
+            false => (FrameKind::Message, false),
+        };
+        if compressed && !cfg!(feature = "compression") {
+            return Err(ParseError::Corrupt);
+        }
+        Ok(Span {
+            kind,
+            channel,
+            body: header..header + size,
+            total,
+            compressed,
+        })
+    }
+    /// Number of bytes that must be read before `buffer` could hold a complete frame, or 0 if
+    /// it already does (or is corrupt)
+    pub(crate) fn needed(buffer: &[u8], format: &FrameFormat, limit: usize) -> usize {
+        if !matches!(
+            Frame::locate(buffer, format, limit),
+            Err(ParseError::NotReady)
+        ) {
+            return 0;
+        }
+        let offset = format.magic_size();
+        if buffer.len() < offset {
+            return offset - buffer.len();
+        }
+        match (format.decode_length(&buffer[offset..]), format.width.size()) {
+            (Ok((size, width)), _) => {
+                offset + width + format.fields_size() + size as usize + format.trailer_size()
+                    - buffer.len()
+            }
+            (Err(_), Some(width)) => offset + width - buffer.len(),
+            (Err(_), None) => 1,
+        }
+    }
+    /// Number of bytes this frame occupies once encoded with `format`
+    pub fn encoded_len(&self, format: &FrameFormat) -> usize {
+        format.framed_len(self.message.len())
+    }
+    /// Serialize a Frame into a framed vector of bytes laid out according to `format`
+    pub fn encode(self, format: &FrameFormat) -> std::result::Result<Vec<u8>, ParseError> {
+        let mut result = Vec::with_capacity(self.encoded_len(format));
+        format.encode_into(self.channel, &self.message, &mut result)?;
+        Ok(result)
     }
 }
 
@@ -56,12 +690,7 @@ impl Frame {
 impl TryInto<Vec<u8>> for Frame {
     type Error = ParseError;
     fn try_into(self) -> std::result::Result<Vec<u8>, Self::Error> {
-        let size: u32 = self.0.len().try_into().map_err(|_| ParseError::Corrupt)?;
-        let header = size.to_le_bytes();
-        let mut result = Vec::new();
-        result.extend(header);
-        result.extend(self.0);
-        Ok(result)
+        self.encode(&FrameFormat::default())
     }
 }
 
@@ -83,7 +712,8 @@ mod frame_test {
         let frame = Frame::new(message.clone());
         let mut buffer: Vec<u8> = frame.try_into().unwrap();
         assert_eq!(buffer.len(), message.len() + 4, "message wrapped in frame");
-        let parsed_frame = Frame::try_from(&mut buffer).unwrap();
+        let parsed_frame =
+            Frame::try_from(&mut buffer, &FrameFormat::default(), usize::MAX).unwrap();
         assert_eq!(buffer.len(), 0, "consumed buffer");
         let parsed_message = parsed_frame.into_message();
         assert_eq!(message, parsed_message);
@@ -95,7 +725,7 @@ mod frame_test {
         let frame = Frame::new(message.clone());
         let mut buffer: Vec<u8> = frame.try_into().unwrap();
         buffer.truncate(128);
-        let error = Frame::try_from(&mut buffer);
+        let error = Frame::try_from(&mut buffer, &FrameFormat::default(), usize::MAX);
         match error {
             Err(ParseError::NotReady) => {}
             Err(e) => panic!("unexpected error: {}", e),
@@ -104,6 +734,92 @@ mod frame_test {
         assert_eq!(buffer.len(), 128);
     }
 
+    #[test]
+    fn parse_slice() {
+        let messages = [random(128), random(7)];
+        let mut input = Vec::new();
+        for message in messages.iter() {
+            let bytes: Vec<u8> = Frame::new(message.clone()).try_into().unwrap();
+            input.extend(bytes);
+        }
+        let format = FrameFormat::default();
+        let (frame, consumed) = Frame::parse(&input, &format, usize::MAX).unwrap();
+        assert_eq!(consumed, 4 + 128);
+        assert_eq!(frame.into_message(), messages[0]);
+        let (frame, consumed) = Frame::parse(&input[132..], &format, usize::MAX).unwrap();
+        assert_eq!(consumed, 4 + 7);
+        assert_eq!(frame.into_message(), messages[1]);
+        assert_eq!(input.len(), 132 + 11, "input is left untouched");
+    }
+
+    #[test]
+    fn parse_slice_not_ready() {
+        let bytes: Vec<u8> = Frame::new(random(16)).try_into().unwrap();
+        for end in 0..bytes.len() {
+            let error = Frame::parse(&bytes[..end], &FrameFormat::default(), usize::MAX);
+            assert_eq!(error.unwrap_err(), ParseError::NotReady);
+        }
+        let format = FrameFormat {
+            tagged: true,
+            ..Default::default()
+        };
+        let mut input = format.encode_header(FrameKind::Ping, 0, 0).unwrap();
+        input.extend(Frame::new(random(16)).encode(&format).unwrap());
+        let (_, consumed) = Frame::parse(&input, &format, usize::MAX).unwrap();
+        assert_eq!(
+            consumed,
+            input.len(),
+            "pings ahead of the message count as consumed"
+        );
+    }
+
+    #[test]
+    fn inclusive_length() {
+        for width in [HeaderWidth::U8, HeaderWidth::U32, HeaderWidth::Varint] {
+            let format = FrameFormat {
+                width,
+                length: LengthSemantics::Inclusive,
+                ..Default::default()
+            };
+            for size in [0, 5, 120, 126, 127, 200] {
+                let message = random(size);
+                let mut buffer = Frame::new(message.clone()).encode(&format).unwrap();
+                assert_eq!(buffer.len(), format.framed_len(size));
+                let (length, prefix) = format.decode_prefix(&buffer).unwrap();
+                assert_eq!(
+                    length as usize,
+                    buffer.len(),
+                    "length counts the whole frame"
+                );
+                assert_eq!(prefix + size, buffer.len());
+                let frame = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+                assert_eq!(frame.into_message(), message);
+            }
+        }
+        let format = FrameFormat {
+            length: LengthSemantics::Inclusive,
+            endianness: Endianness::Big,
+            ..Default::default()
+        };
+        let buffer = Frame::new(b"abc".to_vec()).encode(&format).unwrap();
+        assert_eq!(&buffer[..4], &[0, 0, 0, 7]);
+        let error = Frame::try_from(&mut vec![0, 0, 0, 3, 1, 2, 3], &format, usize::MAX);
+        assert_eq!(error.unwrap_err(), ParseError::Corrupt);
+    }
+
+    #[test]
+    fn payload_only_length() {
+        let format = FrameFormat {
+            endianness: Endianness::Big,
+            ..Default::default()
+        };
+        assert_eq!(format.length, LengthSemantics::PayloadOnly);
+        let mut buffer = Frame::new(b"abc".to_vec()).encode(&format).unwrap();
+        assert_eq!(&buffer[..4], &[0, 0, 0, 3]);
+        let frame = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+        assert_eq!(frame.into_message(), b"abc");
+    }
+
     #[test]
     fn parse_multiple() {
         let messages = [random(128), random(128), random(128)];
@@ -114,7 +830,7 @@ mod frame_test {
             buffer.extend(bytes);
         }
         let mut i = 0;
-        while let Ok(frame) = Frame::try_from(&mut buffer) {
+        while let Ok(frame) = Frame::try_from(&mut buffer, &FrameFormat::default(), usize::MAX) {
             let message = frame.into_message();
             assert_eq!(messages[i], message);
             i += 1;
@@ -123,20 +839,477 @@ mod frame_test {
         assert_eq!(buffer.len(), 0);
     }
 
+    #[test]
+    fn frame_iter() {
+        let messages = [random(128), Vec::new(), random(3)];
+        let mut buffer: Vec<u8> = Vec::new();
+        for message in &messages {
+            buffer.extend(
+                Frame::new(message.clone())
+                    .encode(&FrameFormat::default())
+                    .unwrap(),
+            );
+        }
+        let partial = Frame::new(random(16))
+            .encode(&FrameFormat::default())
+            .unwrap();
+        buffer.extend(&partial[..10]);
+        let parsed: Vec<_> = frames(&mut buffer)
+            .map(|frame| frame.unwrap().into_message())
+            .collect();
+        assert_eq!(parsed, messages);
+        assert_eq!(buffer, partial[..10]);
+        let format = FrameFormat {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut buffer = Frame::new(random(8)).encode(&format).unwrap();
+        buffer.extend(&buffer.clone());
+        buffer[4] ^= 1;
+        let mut iter = format.frames(&mut buffer);
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            ParseError::ChecksumMismatch
+        );
+        assert!(iter.next().is_none());
+    }
+
     #[test]
     fn parse_with_extra() {
         let message = random(128);
         let frame = Frame::new(message.clone());
         let mut buffer: Vec<u8> = frame.try_into().unwrap();
         buffer.extend(random(3));
-        if let Err(e) = Frame::try_from(&mut buffer) {
+        if let Err(e) = Frame::try_from(&mut buffer, &FrameFormat::default(), usize::MAX) {
             panic!("unexpected error: {}", e);
         }
-        match Frame::try_from(&mut buffer) {
+        match Frame::try_from(&mut buffer, &FrameFormat::default(), usize::MAX) {
             Err(ParseError::NotReady) => {}
             Err(e) => panic!("unexpected error: {}", e),
             Ok(_) => panic!("unexpected success"),
         }
         assert_eq!(buffer.len(), 3);
     }
+
+    #[test]
+    fn parse_empty() {
+        let mut buffer = Vec::new();
+        for _ in 0..3 {
+            let bytes: Vec<u8> = Frame::new(Vec::new()).try_into().unwrap();
+            buffer.extend(bytes);
+        }
+        assert_eq!(buffer, vec![0; 12]);
+        for _ in 0..3 {
+            let frame = Frame::try_from(&mut buffer, &FrameFormat::default(), 0).unwrap();
+            assert!(frame.into_message().is_empty());
+        }
+        let error = Frame::try_from(&mut buffer, &FrameFormat::default(), 0);
+        assert_eq!(error.unwrap_err(), ParseError::NotReady);
+    }
+
+    #[test]
+    fn tagged() {
+        let format = FrameFormat {
+            tagged: true,
+            ..Default::default()
+        };
+        let message = random(16);
+        let mut buffer = format.encode_header(FrameKind::Ping, 0, 0).unwrap();
+        assert_eq!(buffer, vec![0, 0, 0, 0, FrameKind::Ping as u8]);
+        buffer.extend(Frame::new(message.clone()).encode(&format).unwrap());
+        assert_eq!(buffer.len(), 5 + format.framed_len(16));
+        assert_eq!(Frame::peek(&buffer, &format, usize::MAX).unwrap(), 10..26);
+        let frame = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+        assert_eq!(frame.into_message(), message);
+        assert!(buffer.is_empty());
+        let error = Frame::try_from(&mut vec![0, 0, 0, 0, 7], &format, usize::MAX);
+        assert_eq!(error.unwrap_err(), ParseError::Corrupt);
+    }
+
+    #[test]
+    fn channels() {
+        let format = FrameFormat {
+            channels: true,
+            endianness: Endianness::Big,
+            ..Default::default()
+        };
+        let mut buffer = Frame::with_channel(2, vec![7; 3]).encode(&format).unwrap();
+        assert_eq!(buffer, vec![0, 0, 0, 3, 0, 2, 7, 7, 7]);
+        let partial = &mut buffer[..5].to_vec();
+        let error = Frame::try_from(partial, &format, usize::MAX);
+        assert_eq!(error.unwrap_err(), ParseError::NotReady);
+        let frame = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+        assert_eq!(frame.channel(), 2);
+        assert_eq!(frame.into_message(), vec![7; 3]);
+    }
+
+    #[test]
+    fn peek() {
+        let message = random(128);
+        let buffer: Vec<u8> = Frame::new(message.clone()).try_into().unwrap();
+        let body = Frame::peek(&buffer, &FrameFormat::default(), usize::MAX).unwrap();
+        assert_eq!(body, 4..132);
+        assert_eq!(&buffer[body], &message[..]);
+        assert_eq!(buffer.len(), 132, "buffer untouched");
+    }
+
+    #[test]
+    fn peek_not_ready() {
+        let message = random(128);
+        let mut buffer: Vec<u8> = Frame::new(message).try_into().unwrap();
+        buffer.truncate(128);
+        let error = Frame::peek(&buffer, &FrameFormat::default(), usize::MAX);
+        assert_eq!(error, Err(ParseError::NotReady));
+        assert_eq!(buffer.len(), 128);
+    }
+
+    #[test]
+    fn peek_with_extra() {
+        let message = random(128);
+        let mut buffer: Vec<u8> = Frame::new(message.clone()).try_into().unwrap();
+        buffer.extend(random(3));
+        let body = Frame::peek(&buffer, &FrameFormat::default(), usize::MAX).unwrap();
+        assert_eq!(&buffer[body], &message[..]);
+        assert_eq!(buffer.len(), 135);
+    }
+
+    #[test]
+    fn encoded_len() {
+        let mut formats = vec![FrameFormat::default(), magic_format(1)];
+        for width in [HeaderWidth::U8, HeaderWidth::U64, HeaderWidth::Varint] {
+            formats.push(FrameFormat {
+                width,
+                checksum: true,
+                ..Default::default()
+            });
+        }
+        for format in formats {
+            for len in [0, 1, 127, 128, 200, 20_000] {
+                let frame = Frame::new(random(len));
+                let expected = frame.encoded_len(&format);
+                match frame.encode(&format) {
+                    Ok(buffer) => assert_eq!(buffer.len(), expected, "{:?}", format),
+                    Err(e) => assert_eq!((e, format.width), (ParseError::Corrupt, HeaderWidth::U8)),
+                }
+            }
+        }
+        assert_eq!(framed_len(128), 132);
+    }
+
+    #[test]
+    fn header_widths() {
+        for width in [
+            HeaderWidth::U8,
+            HeaderWidth::U16,
+            HeaderWidth::U32,
+            HeaderWidth::U64,
+            HeaderWidth::Packed,
+        ] {
+            let format = FrameFormat {
+                width,
+                ..Default::default()
+            };
+            let message = random(128);
+            let mut buffer = Frame::new(message.clone()).encode(&format).unwrap();
+            assert_eq!(buffer.len(), message.len() + width.size().unwrap());
+            buffer.extend(random(3));
+            let parsed = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+            assert_eq!(message, parsed.into_message());
+            assert_eq!(buffer.len(), 3);
+        }
+    }
+
+    #[test]
+    fn packed() {
+        for (endianness, prefix) in [
+            (Endianness::Little, [3, 0, 0, 7]),
+            (Endianness::Big, [7, 0, 0, 3]),
+        ] {
+            let format = FrameFormat {
+                width: HeaderWidth::Packed,
+                endianness,
+                ..Default::default()
+            };
+            let mut buffer = Frame::with_channel(7, vec![1; 3]).encode(&format).unwrap();
+            assert_eq!(buffer[..4], prefix);
+            let frame = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+            assert_eq!(frame.channel(), 7);
+            assert_eq!(frame.into_message(), vec![1; 3]);
+            // The type byte doesn't count towards the length
+            let mut buffer = Frame::with_channel(0xFF, vec![0; 0xFF_FFFF])
+                .encode(&format)
+                .unwrap();
+            assert_eq!(buffer.len(), 0xFF_FFFF + 4);
+            let frame = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+            assert_eq!(frame.channel(), 0xFF);
+            assert_eq!(frame.into_message().len(), 0xFF_FFFF);
+            let error = Frame::new(vec![0; 0x100_0000]).encode(&format);
+            assert_eq!(error.unwrap_err(), ParseError::Corrupt);
+            let error = Frame::with_channel(0x100, Vec::new()).encode(&format);
+            assert_eq!(error.unwrap_err(), ParseError::Corrupt);
+        }
+    }
+
+    #[test]
+    fn header_width_too_small() {
+        let format = FrameFormat {
+            width: HeaderWidth::U8,
+            ..Default::default()
+        };
+        match Frame::new(random(256)).encode(&format) {
+            Err(ParseError::Corrupt) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn header_width_not_ready() {
+        let format = FrameFormat {
+            width: HeaderWidth::U64,
+            ..Default::default()
+        };
+        let mut buffer = Frame::new(random(16)).encode(&format).unwrap();
+        buffer.truncate(7);
+        match Frame::try_from(&mut buffer, &format, usize::MAX) {
+            Err(ParseError::NotReady) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        assert_eq!(buffer.len(), 7);
+    }
+
+    #[test]
+    fn big_endian() {
+        let format = FrameFormat {
+            endianness: Endianness::Big,
+            ..Default::default()
+        };
+        let message = random(300);
+        let mut buffer = Frame::new(message.clone()).encode(&format).unwrap();
+        assert_eq!(buffer[..4], [0, 0, 1, 44]);
+        let parsed = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+        assert_eq!(message, parsed.into_message());
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn endianness_mismatch() {
+        let little = FrameFormat::default();
+        let big = FrameFormat {
+            endianness: Endianness::Big,
+            ..Default::default()
+        };
+        let mut buffer = Frame::new(random(300)).encode(&little).unwrap();
+        match Frame::try_from(&mut buffer, &big, usize::MAX) {
+            Err(ParseError::NotReady) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        let mut buffer = Frame::new(random(300)).encode(&big).unwrap();
+        match Frame::try_from(&mut buffer, &little, usize::MAX) {
+            Err(ParseError::NotReady) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn length_overflow() {
+        let format = FrameFormat {
+            width: HeaderWidth::U64,
+            ..Default::default()
+        };
+        let mut buffer = vec![0xFF; 8];
+        match Frame::try_from(&mut buffer, &format, u32::MAX as usize) {
+            Err(ParseError::TooLarge {
+                declared: u64::MAX, ..
+            }) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        // A length within the limit that can't be added to the header without overflowing
+        let mut buffer = (usize::MAX as u64).to_le_bytes().to_vec();
+        match Frame::try_from(&mut buffer, &format, usize::MAX) {
+            Err(ParseError::Corrupt) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        assert_eq!(buffer.len(), 8);
+    }
+
+    #[test]
+    fn too_large() {
+        let format = FrameFormat {
+            width: HeaderWidth::U64,
+            ..Default::default()
+        };
+        let mut buffer = u64::MAX.to_le_bytes().to_vec();
+        match Frame::try_from(&mut buffer, &format, 1024) {
+            Err(ParseError::TooLarge {
+                declared: u64::MAX,
+                limit: 1024,
+            }) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        let format = FrameFormat {
+            length: LengthSemantics::Inclusive,
+            ..Default::default()
+        };
+        let mut buffer = 2u32.to_le_bytes().to_vec();
+        match Frame::try_from(&mut buffer, &format, 1024) {
+            Err(ParseError::Corrupt) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn limit() {
+        let mut buffer: Vec<u8> = Frame::new(random(256)).try_into().unwrap();
+        buffer.truncate(4);
+        match Frame::try_from(&mut buffer, &FrameFormat::default(), 128) {
+            Err(ParseError::TooLarge {
+                declared: 256,
+                limit: 128,
+            }) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        let mut buffer: Vec<u8> = Frame::new(random(128)).try_into().unwrap();
+        assert!(Frame::try_from(&mut buffer, &FrameFormat::default(), 128).is_ok());
+    }
+
+    #[test]
+    fn checksum() {
+        let format = FrameFormat {
+            checksum: true,
+            ..Default::default()
+        };
+        let message = random(128);
+        let mut buffer = Frame::new(message.clone()).encode(&format).unwrap();
+        assert_eq!(buffer.len(), message.len() + 8);
+        buffer.extend(random(3));
+        let parsed = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+        assert_eq!(message, parsed.into_message());
+        assert_eq!(buffer.len(), 3);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let format = FrameFormat {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut buffer = Frame::new(random(128)).encode(&format).unwrap();
+        buffer[64] ^= 0x01;
+        match Frame::try_from(&mut buffer, &format, usize::MAX) {
+            Err(ParseError::ChecksumMismatch) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        assert_eq!(buffer.len(), 136);
+    }
+
+    fn magic_format(version: u8) -> FrameFormat {
+        FrameFormat {
+            magic: Some(Magic {
+                prefix: b"MSNK".to_vec(),
+                version,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn magic() {
+        let format = magic_format(1);
+        let message = random(128);
+        let mut buffer = Frame::new(message.clone()).encode(&format).unwrap();
+        assert_eq!(buffer[..5], *b"MSNK\x01");
+        assert_eq!(buffer.len(), message.len() + 9);
+        let parsed = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+        assert_eq!(message, parsed.into_message());
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn wrong_magic() {
+        let format = magic_format(1);
+        let mut buffer = b"GET / HTTP/1.1".to_vec();
+        match Frame::try_from(&mut buffer, &format, usize::MAX) {
+            Err(ParseError::Corrupt) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        // A mismatch is detected as soon as the first byte arrives
+        let mut buffer = b"G".to_vec();
+        match Frame::try_from(&mut buffer, &format, usize::MAX) {
+            Err(ParseError::Corrupt) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn unknown_version() {
+        let mut buffer = Frame::new(random(128)).encode(&magic_format(2)).unwrap();
+        match Frame::try_from(&mut buffer, &magic_format(1), usize::MAX) {
+            Err(ParseError::Corrupt) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn varint() {
+        let format = FrameFormat {
+            width: HeaderWidth::Varint,
+            ..Default::default()
+        };
+        for (length, header) in [(1, 1), (127, 1), (128, 2), (100_000, 3)] {
+            let message = random(length);
+            let mut buffer = Frame::new(message.clone()).encode(&format).unwrap();
+            assert_eq!(buffer.len(), length + header);
+            let parsed = Frame::try_from(&mut buffer, &format, usize::MAX).unwrap();
+            assert_eq!(message, parsed.into_message());
+            assert_eq!(buffer.len(), 0);
+        }
+    }
+
+    #[test]
+    fn varint_partial() {
+        let format = FrameFormat {
+            width: HeaderWidth::Varint,
+            ..Default::default()
+        };
+        let mut buffer = Frame::new(random(100_000)).encode(&format).unwrap();
+        let mut partial = buffer[..2].to_vec();
+        match Frame::try_from(&mut partial, &format, usize::MAX) {
+            Err(ParseError::NotReady) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        buffer.truncate(3);
+        match Frame::try_from(&mut buffer, &format, usize::MAX) {
+            Err(ParseError::NotReady) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn varint_overflow() {
+        let format = FrameFormat {
+            width: HeaderWidth::Varint,
+            ..Default::default()
+        };
+        let mut buffer = vec![0xFF; 11];
+        match Frame::try_from(&mut buffer, &format, usize::MAX) {
+            Err(ParseError::Corrupt) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
 }
@@ -1,25 +1,93 @@
+mod ack;
 mod async_buffer;
+mod builder;
+mod checksum;
+mod codec;
+#[cfg(feature = "compression")]
+mod compression;
+mod duplex;
+mod forward;
 mod frame;
+mod observer;
+mod reader;
+mod sans_io;
+mod split;
+mod take;
+mod timer;
+#[cfg(feature = "serde")]
+mod typed;
+mod write_only;
+mod writer;
 
+pub use ack::WriteAck;
 use async_buffer::AsyncBuffer;
-use frame::{Frame, ParseError};
+pub use builder::MessageSinkBuilder;
+#[cfg(feature = "bytes")]
+pub use bytes::Bytes;
+pub use codec::{Codec, DelimiterCodec, FixedCodec, FrameParts};
+#[cfg(feature = "compression")]
+pub use compression::Compression;
+pub use duplex::Duplex;
+pub use forward::forward;
+pub use frame::{
+    framed_len, frames, Chunk, Endianness, Frame, FrameFormat, FrameIter, HeaderWidth,
+    LengthSemantics, Magic, ParseError, Span,
+};
 use futures::{
-    io::{AsyncRead, AsyncWrite},
-    Future,
+    future::poll_fn,
+    io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite},
+    ready,
+    stream::FusedStream,
+    Future, Sink, Stream,
 };
+use observer::Observed;
+pub use observer::Observer;
+use reader::{FillFn, FrameReader};
+pub use sans_io::{FrameDecoder, FrameEncoder};
+pub use split::{SinkReader, SinkWriter};
 use std::{
     error::Error,
-    fmt::Display,
+    fmt::{self, Debug, Display},
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
+pub use take::TakeSink;
+use timer::IdleTimer;
+pub use timer::Timer;
+#[cfg(feature = "serde")]
+pub use typed::{Bincode, Encoding, TypedSink};
+#[cfg(feature = "json")]
+pub use typed::{Json, JsonSink};
+pub use write_only::WriteOnlySink;
+use writer::{FrameWriter, WriteHalf};
 
 #[derive(Debug)]
 pub enum SinkError {
     Write(std::io::Error),
     Read(std::io::Error),
     LimitExceeded,
+    WriteBufferFull,
+    Parse(ParseError),
+    Timeout,
+    /// The stream ended partway through a frame
+    UnexpectedEof,
+    Codec(Box<dyn Error + Send + Sync>),
+    Closed,
+}
+
+/// A cloneable summary of a [`SinkError`], keeping the `io::ErrorKind` of stream errors
+/// and dropping codec error details, for when the same error must reach several consumers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkErrorKind {
+    Write(std::io::ErrorKind),
+    Read(std::io::ErrorKind),
+    LimitExceeded,
+    WriteBufferFull,
     Parse(ParseError),
+    Timeout,
+    UnexpectedEof,
+    Codec,
     Closed,
 }
 
@@ -29,139 +97,941 @@ impl Display for SinkError {
             SinkError::Write(e) => write!(f, "Write Error: {}", e),
             SinkError::Read(e) => write!(f, "Read Error: {}", e),
             SinkError::LimitExceeded => write!(f, "Limit Exceeded"),
+            SinkError::WriteBufferFull => write!(f, "Write Buffer Full"),
             SinkError::Parse(e) => write!(f, "Parse Error: {}", e),
+            SinkError::Timeout => write!(f, "Read Timeout"),
+            SinkError::UnexpectedEof => write!(f, "Unexpected EOF: stream ended mid-frame"),
+            SinkError::Codec(e) => write!(f, "Codec Error: {}", e),
             SinkError::Closed => write!(f, "Stream Error: poll after closed"),
         }
     }
 }
 
-impl Error for SinkError {}
+impl SinkError {
+    /// Wrap an error returned while reading from the underlying stream
+    pub fn read(e: std::io::Error) -> Self {
+        SinkError::Read(e)
+    }
+    /// Wrap an error returned while writing to the underlying stream
+    pub fn write(e: std::io::Error) -> Self {
+        SinkError::Write(e)
+    }
+    /// Wrap an error returned while converting a value to or from a message
+    pub fn codec(e: impl Error + Send + Sync + 'static) -> Self {
+        SinkError::Codec(Box::new(e))
+    }
+    /// A copy of this error, with stream and codec errors rebuilt from their messages
+    fn duplicate(&self) -> SinkError {
+        match self {
+            SinkError::Write(e) => SinkError::Write(std::io::Error::new(e.kind(), e.to_string())),
+            SinkError::Read(e) => SinkError::Read(std::io::Error::new(e.kind(), e.to_string())),
+            SinkError::LimitExceeded => SinkError::LimitExceeded,
+            SinkError::WriteBufferFull => SinkError::WriteBufferFull,
+            SinkError::Parse(e) => SinkError::Parse(*e),
+            SinkError::Timeout => SinkError::Timeout,
+            SinkError::UnexpectedEof => SinkError::UnexpectedEof,
+            SinkError::Codec(e) => SinkError::Codec(e.to_string().into()),
+            SinkError::Closed => SinkError::Closed,
+        }
+    }
+    pub fn kind(&self) -> SinkErrorKind {
+        match self {
+            SinkError::Write(e) => SinkErrorKind::Write(e.kind()),
+            SinkError::Read(e) => SinkErrorKind::Read(e.kind()),
+            SinkError::LimitExceeded => SinkErrorKind::LimitExceeded,
+            SinkError::WriteBufferFull => SinkErrorKind::WriteBufferFull,
+            SinkError::Parse(e) => SinkErrorKind::Parse(*e),
+            SinkError::Timeout => SinkErrorKind::Timeout,
+            SinkError::UnexpectedEof => SinkErrorKind::UnexpectedEof,
+            SinkError::Codec(_) => SinkErrorKind::Codec,
+            SinkError::Closed => SinkErrorKind::Closed,
+        }
+    }
+}
+
+impl From<ParseError> for SinkError {
+    fn from(e: ParseError) -> Self {
+        SinkError::Parse(e)
+    }
+}
+
+impl Error for SinkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SinkError::Write(e) | SinkError::Read(e) => Some(e),
+            SinkError::Parse(e) => Some(e),
+            SinkError::Codec(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Running totals of the traffic a sink has handled. Byte counts include frame headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SinkStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub messages_read: u64,
+    pub messages_written: u64,
+}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SinkStatus {
     Open,
+    /// `close` was called and queued messages are still being written
+    Flushing,
     Closing,
     Closed,
+    /// Reading, writing or parsing failed. Polls resolve to the same error from then on.
+    Errored,
 }
 
-pub struct MessageSink<S>
+/// Frames messages over `S` with the codec `C`, length-prefixed [`FrameFormat`] framing by
+/// default. Dropping the sink loses any messages not yet written, use
+/// [`shutdown`](MessageSink::shutdown) to tear it down cleanly.
+pub struct MessageSink<S, C = FrameFormat>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     stream: S,
-    read_buffer: Vec<u8>,
-    write_buffer: AsyncBuffer,
-    scratch: [u8; 1024],
+    reader: FrameReader<C>,
+    writer: FrameWriter<C>,
     status: SinkStatus,
-    limit: usize,
+    /// The error that closed the sink, if any
+    error: Option<SinkError>,
+    /// How bytes are pulled from `stream`, see `from_buf_read`
+    fill: FillFn<C, S>,
+    /// Messages left to deliver before closing, see `max_messages`
+    remaining: Option<usize>,
+    observed: Observed,
 }
 
 impl<S> MessageSink<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
+    /// Wrap `socket` with the default configuration. Use [`MessageSinkBuilder`] to configure
+    /// the sink before it is first polled.
     pub fn new(socket: S) -> Self {
+        MessageSinkBuilder::new().build(socket)
+    }
+    /// Like `new`, but reads and queues writes into the allocations of `read_buf` and
+    /// `write_buf`, e.g. taken from a pool, instead of allocating its own. Their contents are
+    /// discarded, and a `write_buf` over 4 KiB is dropped since no more than that is kept
+    /// for small writes. [`MessageSink::into_buffers`] returns them for the next sink.
+    pub fn with_buffers(socket: S, read_buf: Vec<u8>, write_buf: Vec<u8>) -> Self {
+        let mut sink = Self::new(socket);
+        sink.reader.recycle(read_buf);
+        sink.writer.buffer = AsyncBuffer::recycle(write_buf);
+        sink
+    }
+    /// Like `new`, but with room for `read_cap` bytes in the read buffer and `write_cap`
+    /// bytes of small writes in the write buffer before either has to grow. The read buffer
+    /// is never shrunk below `read_cap`. Messages too large to be coalesced are queued in
+    /// their own allocation, so they need no write capacity.
+    pub fn with_capacity(socket: S, read_cap: usize, write_cap: usize) -> Self {
+        let mut sink = Self::with_buffers(
+            socket,
+            Vec::with_capacity(read_cap),
+            Vec::with_capacity(write_cap),
+        );
+        sink.reader.capacity = read_cap;
+        sink
+    }
+    /// Tag each frame with the channel it was sent on, so several logical streams can share
+    /// the connection. The peer must enable channels too.
+    pub fn channels(&mut self, enabled: bool) {
+        self.reader.format.channels = enabled;
+        self.writer.format.channels = enabled;
+    }
+    /// Send a ping whenever nothing has been written for `interval` while the sink is being
+    /// polled, and drop pings from the peer. Frames carry a kind byte to tell pings apart, so
+    /// the peer must enable keepalive too.
+    pub fn keepalive(&mut self, interval: Duration, timer: impl Timer + 'static) {
+        self.reader.format.tagged = true;
+        self.writer.format.tagged = true;
+        self.writer.keepalive = Some(IdleTimer::new(interval, timer));
+    }
+    /// Width of the length prefix used when framing messages in both directions
+    pub fn header_width(&mut self, width: HeaderWidth) {
+        self.reader.format.width = width;
+        self.writer.format.width = width;
+    }
+    /// Byte order of the length prefix used when framing messages in both directions
+    pub fn endianness(&mut self, endianness: Endianness) {
+        self.reader.format.endianness = endianness;
+        self.writer.format.endianness = endianness;
+    }
+    /// Whether the length prefix counts itself as well as the payload, in both directions.
+    /// Inbound inclusive lengths shorter than the prefix are rejected as
+    /// `ParseError::Corrupt`.
+    pub fn length_semantics(&mut self, length: LengthSemantics) {
+        self.reader.format.length = length;
+        self.writer.format.length = length;
+    }
+    /// Frame messages the way `tokio_util::codec::LengthDelimitedCodec` does by default, a
+    /// big-endian u32 length of the payload alone, so either side can read the other's
+    /// frames. Equivalent to `HeaderWidth::U32` with `Endianness::Big`.
+    pub fn tokio_compat(&mut self) {
+        self.header_width(HeaderWidth::U32);
+        self.endianness(Endianness::Big);
+    }
+    /// Start every frame with `prefix` followed by a `version` byte, rejecting inbound frames
+    /// that don't match either as `ParseError::Corrupt`
+    pub fn magic(&mut self, prefix: &[u8], version: u8) {
+        let magic = Magic {
+            prefix: prefix.to_vec(),
+            version,
+        };
+        self.reader.format.magic = Some(magic.clone());
+        self.writer.format.magic = Some(magic);
+    }
+    /// Deflate outbound messages per `compression`, or stop compressing with `None`. Frames
+    /// carry a kind byte to flag compressed bodies, so the peer must enable compression,
+    /// or keepalive, too. Compressed frames from the peer are inflated either way, and fail
+    /// with `SinkError::LimitExceeded` as soon as they inflate past `limit`.
+    #[cfg(feature = "compression")]
+    pub fn compression(&mut self, compression: Option<Compression>) {
+        self.reader.format.tagged = true;
+        self.writer.format.tagged = true;
+        self.writer.format.compression = compression;
+    }
+    /// Treat every read from the stream as one whole message, for packet-oriented streams
+    /// that return a single datagram per read. Inbound bytes carry no framing, so a read
+    /// of 0 bytes means EOF rather than an empty message. Datagrams longer than `limit`
+    /// fail with `ParseError::TooLarge`. Each read has room for `limit` bytes, or 64 KiB
+    /// if that is more or no limit is set, plus one: a datagram that fills it may have
+    /// been truncated and fails with an `InvalidData` read error. Writes are framed as
+    /// usual.
+    pub fn datagram(&mut self, enabled: bool) {
+        self.reader.datagram = enabled;
+        self.fill = match enabled {
+            true => FrameReader::poll_datagram,
+            false => FrameReader::poll_read,
+        };
+    }
+    /// Have `recv_chunk` yield messages longer than `bytes` in chunks as their body arrives,
+    /// so they never need to be buffered whole. `None`, the default, buffers every message
+    /// whole. Frames with a checksum or a compressed body are always buffered whole.
+    pub fn chunk_threshold(&mut self, bytes: Option<usize>) {
+        self.reader.chunk_threshold = bytes;
+    }
+    /// Receive the next part of a message, see `chunk_threshold`. Messages at or below the
+    /// threshold arrive as a single chunk. Once the first chunk of a message has been
+    /// returned, keep calling `recv_chunk` until one has `is_last` set, other ways of
+    /// receiving would start reading partway through its body.
+    pub async fn recv_chunk(&mut self) -> Result<Option<Chunk>, SinkError> {
+        poll_fn(|cx| self.poll_with(cx, FrameReader::poll_next_chunk)).await
+    }
+    /// Append a CRC32 of each message to its frame and reject inbound frames whose
+    /// checksum doesn't match with `ParseError::ChecksumMismatch`
+    pub fn checksum(&mut self, enabled: bool) {
+        self.reader.format.checksum = enabled;
+        self.writer.format.checksum = enabled;
+    }
+}
+
+impl<R, W> MessageSink<Duplex<R, W>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Like `new`, for a transport whose read and write halves are separate types, e.g.
+    /// after splitting it some other way. `into_inner` hands back both halves as a
+    /// [`Duplex`].
+    pub fn from_halves(reader: R, writer: W) -> Self {
+        Self::new(Duplex::new(reader, writer))
+    }
+}
+
+impl<R, W, C> MessageSink<Duplex<R, W>, C>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    C: Codec + Clone,
+{
+    /// Like `from_halves`, framing messages with `codec`
+    pub fn from_halves_with_codec(reader: R, writer: W, codec: C) -> Self {
+        Self::with_codec(Duplex::new(reader, writer), codec)
+    }
+}
+
+impl<S> MessageSink<S>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    /// Like `new`, for a stream that already buffers what it reads, e.g. a `BufReader`.
+    /// Frames that arrive whole are parsed straight out of the stream's buffer instead of
+    /// being copied into the sink's own read buffer first.
+    pub fn from_buf_read(stream: S) -> Self {
+        let mut sink = Self::new(stream);
+        sink.fill = FrameReader::poll_fill_buf;
+        sink
+    }
+}
+
+impl<S, C> MessageSink<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    /// Wrap `socket`, framing messages with `codec` instead of the default length prefix
+    pub fn with_codec(socket: S, codec: C) -> Self
+    where
+        C: Clone,
+    {
+        let reader = FrameReader::new(codec.clone());
+        Self::from_parts(socket, reader, FrameWriter::new(codec))
+    }
+    pub(crate) fn from_parts(stream: S, reader: FrameReader<C>, writer: FrameWriter<C>) -> Self {
         Self {
-            stream: socket,
-            read_buffer: Default::default(),
-            write_buffer: Default::default(),
-            scratch: [0; 1024],
+            stream,
+            reader,
+            writer,
             status: SinkStatus::Open,
-            limit: usize::MAX,
+            error: None,
+            fill: FrameReader::poll_read,
+            remaining: None,
+            observed: Observed::default(),
         }
     }
+    /// Maximum size of a single inbound message, enforced against the declared length of
+    /// each frame before its body is buffered
     pub fn limit(&mut self, length: usize) {
-        self.limit = length;
+        self.reader.limit = length;
+    }
+    /// The maximum size of a single inbound message, `usize::MAX` unless set by `limit` or
+    /// agreed in a `handshake`
+    pub fn current_limit(&self) -> usize {
+        self.reader.limit
+    }
+    /// Report messages read and written, and the error that closes the sink, to `observer`
+    pub fn observer(&mut self, observer: impl Observer + 'static) {
+        let written = self.writer.messages_written;
+        self.observed.set(Box::new(observer), written);
+    }
+    /// Call the observer's `on_slow_consumer` once inbound bytes waiting to be received grow
+    /// past `bytes`, as an early warning before `limit` is reached. The sink stays open,
+    /// and the warning fires again only after the backlog has dropped back to `bytes`.
+    pub fn read_buffer_warn_threshold(&mut self, bytes: Option<usize>) {
+        self.observed.warn_threshold = bytes;
+    }
+    /// Agree on a maximum message size with the peer, which must call `handshake` too.
+    /// Each side sends its `limit` as a little-endian `u32` in a frame of its own before
+    /// any other message, and both adopt the smaller of the two: longer inbound messages
+    /// fail as they would over `limit`, and longer writes with `SinkError::LimitExceeded`.
+    /// A first frame that isn't a non-zero `u32` fails with `ParseError::Corrupt`. Resolves
+    /// to the agreed size.
+    pub async fn handshake(&mut self, limit: u32) -> Result<usize, SinkError> {
+        self.write(limit.to_le_bytes().to_vec())?;
+        self.flush().await?;
+        // Frames arriving behind the peer's are parsed against our own limit at most
+        self.reader.limit = limit as usize;
+        let advertised = match self.recv().await? {
+            Some(message) => <[u8; 4]>::try_from(message).map(u32::from_le_bytes),
+            None => return Err(SinkError::UnexpectedEof),
+        };
+        let agreed = match advertised {
+            Ok(advertised) if advertised > 0 => limit.min(advertised) as usize,
+            _ => return Err(self.fail(SinkError::Parse(ParseError::Corrupt))),
+        };
+        self.reader.limit = agreed;
+        self.writer.max_message = agreed;
+        Ok(agreed)
+    }
+    /// Number of bytes to attempt to read from the stream per `poll_read` call, 8 KiB by
+    /// default
+    pub fn read_chunk_size(&mut self, bytes: usize) {
+        self.reader.chunk = bytes;
+    }
+    /// Parse at most `n` buffered frames at a time, including the one being yielded. The
+    /// stream is not read again until every complete frame already buffered has been
+    /// consumed, so this bounds the parsed backlog a pipelining peer can build up.
+    pub fn max_pending_frames(&mut self, n: usize) {
+        self.reader.max_pending = n.max(1);
+    }
+    /// Shrink the read buffer after a burst of large messages once its capacity exceeds
+    /// `multiple` times the buffered bytes plus one read chunk, 4 by default. `None` keeps
+    /// the capacity for reuse instead.
+    pub fn shrink_threshold(&mut self, multiple: Option<usize>) {
+        self.reader.shrink = multiple;
+    }
+    /// Fail with `SinkError::Timeout` if no bytes arrive for `duration` while part of a frame
+    /// is buffered. `timer` supplies the sleeps, e.g. `tokio::time::sleep`.
+    pub fn read_timeout(&mut self, duration: Duration, timer: impl Timer + 'static) {
+        self.reader.timeout = Some(IdleTimer::new(duration, timer));
+    }
+    /// Write queued messages until the outbound buffer is below the high-water mark (or the
+    /// write limit, if that is lower), returning `Pending` while it is still above. Lets a
+    /// producer wait for a slow peer before calling `write`, without going through `Sink`.
+    pub fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SinkError>> {
+        match self.status {
+            SinkStatus::Open => {}
+            SinkStatus::Errored => return Poll::Ready(Err(self.errored())),
+            _ => return Poll::Ready(Err(SinkError::Closed)),
+        }
+        let result = self.writer.poll_ready(&mut self.stream, cx);
+        self.observed.written(self.writer.messages_written);
+        Poll::Ready(ready!(result).map_err(|e| self.fail(e)))
+    }
+    /// Close the sink gracefully once `n` more messages have been delivered, flushing queued
+    /// writes first. Unlike `take`, the stream itself is closed, and later reads resolve to
+    /// `Ok(None)` even if more frames arrive.
+    pub fn max_messages(&mut self, n: usize) {
+        self.remaining = Some(n);
+        if n == 0 {
+            self.close();
+        }
     }
-    pub fn write(&mut self, message: Vec<u8>) -> Result<(), ParseError> {
-        let message: Vec<u8> = Frame::new(message).try_into()?;
-        self.write_buffer.extend(message);
-        Ok(())
+    /// Number of buffered outbound bytes above which `Sink::poll_ready` applies backpressure
+    pub fn high_water_mark(&mut self, bytes: usize) {
+        self.writer.high_water = bytes;
     }
+    /// Coalesce small writes: while fewer than `bytes` are queued, polling the sink for
+    /// messages leaves them buffered rather than writing them out, so a burst of small
+    /// messages goes out in a few large writes. Queued messages are written regardless by
+    /// `flush`, `close` and backpressure. `None`, the default, writes on every poll.
+    pub fn coalesce(&mut self, bytes: Option<usize>) {
+        self.writer.coalesce = bytes;
+    }
+    /// Maximum number of buffered outbound bytes; `write` rejects messages past this point
+    pub fn write_limit(&mut self, bytes: usize) {
+        self.writer.limit = bytes;
+    }
+    /// Frame `message` and queue it to be written, failing with
+    /// `SinkError::WriteBufferFull` if that would grow the outbound buffer past the write
+    /// limit. Empty messages are valid and arrive as empty messages. Returns the length of
+    /// the frame queued, header and trailer included.
+    pub fn write(&mut self, message: Vec<u8>) -> Result<usize, SinkError> {
+        self.writer.write(message)
+    }
+    /// Like `write`, but frames a borrowed `message`, copying it into the outbound buffer
+    /// instead of requiring an owned `Vec`.
+    pub fn write_slice(&mut self, message: &[u8]) -> Result<usize, SinkError> {
+        self.writer.write_slice(message)
+    }
+    /// Like `write`, but returns a [`WriteAck`] that resolves once every byte of this
+    /// message's frame has been written to the stream, e.g. to confirm delivery to the OS
+    /// before acknowledging upstream.
+    pub fn write_acked(&mut self, message: Vec<u8>) -> Result<WriteAck, SinkError> {
+        self.writer.write_acked(message)
+    }
+    /// Frame and queue a batch of messages in one pass. Either every message is queued or,
+    /// on error, none are. Returns the combined length of the frames queued.
+    pub fn write_all(&mut self, messages: Vec<Vec<u8>>) -> Result<usize, SinkError> {
+        self.writer.write_all(messages)
+    }
+    /// Like `write`, but sends `message` on `channel`. Fails with a `ParseError::Corrupt`
+    /// for any channel other than 0 unless channels are enabled, or above 255 with a
+    /// `HeaderWidth::Packed` header.
+    pub fn write_to(&mut self, channel: u16, message: Vec<u8>) -> Result<usize, SinkError> {
+        self.writer.write_frames([(channel, message)])
+    }
+    /// Queue bytes that are already framed, e.g. relayed from another connection, to be
+    /// written as they are. The caller is responsible for `framed` holding only complete,
+    /// valid frames in this sink's format, anything else corrupts the stream for the peer.
+    /// Raw bytes count towards `bytes_written` but not `messages_written`.
+    pub fn write_raw(&mut self, framed: Vec<u8>) -> Result<(), SinkError> {
+        self.writer.write_raw(framed)
+    }
+    /// Close the sink once every queued message has been written, the next time it is
+    /// polled.
     pub fn close(&mut self) {
-        self.status = SinkStatus::Closing;
+        if self.status == SinkStatus::Open {
+            self.status = SinkStatus::Flushing;
+        }
+    }
+    /// Stop sending, like a TCP `shutdown(Write)`: queued messages are written and the
+    /// stream is closed for writing the next time the sink is polled or flushed, while
+    /// messages keep arriving until the peer closes too. Later writes fail with
+    /// `SinkError::Closed`.
+    pub fn close_write(&mut self) {
+        if self.writer.half == WriteHalf::Open {
+            self.writer.half = WriteHalf::Closing;
+        }
+    }
+    /// Write queued messages to the stream, resolving once the outbound buffer is empty and
+    /// the stream has been flushed.
+    pub async fn flush(&mut self) -> Result<(), SinkError> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_flush(cx)).await
+    }
+    /// Check whether the peer has closed its side of the stream, without consuming a
+    /// message. Anything read while probing stays buffered for the next `recv`. Resolves
+    /// to `true` at EOF, or once the sink has closed, and to `false` if more bytes arrived
+    /// instead. A read error counts as closed and is kept for the next `recv`.
+    pub fn poll_peer_closed(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        if !matches!(self.status, SinkStatus::Open | SinkStatus::Flushing) {
+            return Poll::Ready(true);
+        }
+        use std::io::ErrorKind::{Interrupted, WouldBlock};
+        let chunk = self.reader.chunk;
+        match ready!((self.fill)(&mut self.reader, &mut self.stream, cx, chunk)) {
+            Ok(0) => Poll::Ready(true),
+            Ok(filled) => {
+                self.reader.bytes_read += filled as u64;
+                Poll::Ready(false)
+            }
+            Err(e) if matches!(e.kind(), WouldBlock | Interrupted) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Err(e) => {
+                self.fail(SinkError::read(e));
+                Poll::Ready(true)
+            }
+        }
+    }
+    /// Write every queued message, close the stream and consume the sink. `Drop` can't wait
+    /// for the stream, so dropping the sink instead may lose buffered writes. Resolves to
+    /// the error that closed the sink, if one did.
+    pub async fn shutdown(mut self) -> Result<(), SinkError> {
+        poll_fn(|cx| self.poll_drain(cx)).await?;
+        poll_fn(|cx| Sink::poll_close(Pin::new(&mut self), cx)).await
+    }
+    /// Like `poll_flush`, for driving writes from a custom executor loop, e.g. during
+    /// shutdown when inbound frames should not be surfaced. Never reads. Resolves to the
+    /// error that closed the sink, if one did, and to `Ok(())` once a closed sink has
+    /// nothing left to write.
+    pub fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SinkError>> {
+        if let Some(error) = &self.error {
+            return Poll::Ready(Err(error.duplicate()));
+        }
+        if self.status == SinkStatus::Closed {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(self).poll_flush(cx)
+    }
+    /// Drive queued messages out to the stream and flush it, without reading. Resolves once
+    /// the outbound buffer is empty, inbound frames are left on the stream for `recv`.
+    pub fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SinkError>> {
+        let sink = self.get_mut();
+        match sink.status {
+            SinkStatus::Closed => return Poll::Ready(Err(SinkError::Closed)),
+            SinkStatus::Errored => return Poll::Ready(Err(sink.errored())),
+            _ => {}
+        }
+        ready!(sink.poll_write(cx))?;
+        match sink.writer.half {
+            WriteHalf::Open => Pin::new(&mut sink.stream)
+                .poll_flush(cx)
+                .map_err(SinkError::Write),
+            WriteHalf::Closing => sink.writer.poll_shutdown(&mut sink.stream, cx),
+            WriteHalf::Closed => Poll::Ready(Ok(())),
+        }
+    }
+    /// Return the next message if one is already fully buffered, without reading from the
+    /// stream. Resolves to `Ok(None)` if no complete frame is buffered.
+    pub fn try_recv(&mut self) -> Result<Option<Vec<u8>>, SinkError> {
+        if self.status != SinkStatus::Open {
+            return Ok(None);
+        }
+        let result = self.reader.parse();
+        self.delivered(result)
+    }
+    /// Like `try_recv`, but returns the message along with the channel it arrived on
+    pub fn try_recv_from(&mut self) -> Result<Option<(u16, Vec<u8>)>, SinkError> {
+        if self.status != SinkStatus::Open {
+            return Ok(None);
+        }
+        let result = self.reader.parse_from();
+        self.delivered(result)
+    }
+    /// Like `recv`, but returns the message along with the channel it arrived on
+    pub async fn recv_from(&mut self) -> Result<Option<(u16, Vec<u8>)>, SinkError> {
+        poll_fn(|cx| self.poll_with(cx, FrameReader::poll_next_from)).await
+    }
+    /// Borrow the next message if one is already fully buffered, without consuming it or
+    /// reading from the stream. The next `try_recv` or poll yields the same message.
+    pub fn peek(&mut self) -> Result<Option<&[u8]>, SinkError> {
+        if self.status != SinkStatus::Open {
+            return Ok(None);
+        }
+        if let Err(e) = self.reader.fill() {
+            return Err(self.fail(e));
+        }
+        Ok(self
+            .reader
+            .ready
+            .front()
+            .map(|(_, message, _)| &message[..]))
+    }
+    /// Receive the next message, or `Ok(None)` once the sink has closed cleanly.
+    /// This is cancel-safe: partially read frames stay buffered in the sink if the returned
+    /// future is dropped before completing.
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>, SinkError> {
+        poll_fn(|cx| self.poll_message(cx)).await
+    }
+    /// Like `recv`, but copies the message into `out`, replacing its contents, and resolves
+    /// to its length. This is a copy rather than a swap: `out` keeps its allocation, so a
+    /// buffer reused across calls stops growing once it fits the largest message, and frames
+    /// still in the read buffer are copied straight out of it without allocating.
+    pub async fn recv_into(&mut self, out: &mut Vec<u8>) -> Result<Option<usize>, SinkError> {
+        if poll_fn(|cx| self.poll_with(cx, FrameReader::poll_next_ready))
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        self.reader.parse_into(out)
+    }
+    /// Frame `message` and queue it to be written, like `write`
+    #[cfg(feature = "bytes")]
+    pub fn write_bytes(&mut self, message: Bytes) -> Result<usize, SinkError> {
+        self.writer.write_all([message])
+    }
+    /// Like `try_recv`, but returns the message as a slice of the read buffer rather than a
+    /// copy
+    #[cfg(feature = "bytes")]
+    pub fn try_recv_bytes(&mut self) -> Result<Option<Bytes>, SinkError> {
+        if self.status != SinkStatus::Open {
+            return Ok(None);
+        }
+        let result = self.reader.parse_bytes();
+        self.delivered(result)
+    }
+    /// Like `recv`, but returns the message as a slice of the read buffer rather than a copy
+    #[cfg(feature = "bytes")]
+    pub async fn recv_bytes(&mut self) -> Result<Option<Bytes>, SinkError> {
+        poll_fn(|cx| self.poll_with(cx, FrameReader::poll_next_bytes)).await
+    }
+    /// Release read buffer capacity that is not needed for the bytes currently buffered,
+    /// regardless of `shrink_threshold`
+    pub fn compact_read_buffer(&mut self) {
+        self.reader.compact();
+    }
+    pub fn status(&self) -> SinkStatus {
+        self.status
+    }
+    pub fn stats(&self) -> SinkStats {
+        SinkStats {
+            bytes_read: self.reader.bytes_read,
+            bytes_written: self.writer.bytes_written,
+            messages_read: self.reader.messages_read,
+            messages_written: self.writer.messages_written,
+        }
+    }
+    /// The error that closed the sink, if one did. Polls after that resolve to the same
+    /// error again, this keeps the original for inspection without polling.
+    pub fn last_error(&self) -> Option<&SinkError> {
+        self.error.as_ref()
+    }
+    /// A one-line summary of the sink's state for diagnosing framing problems, the same as
+    /// its `Debug` output.
+    pub fn debug_state(&self) -> String {
+        format!("{:?}", self)
+    }
+    /// Whether the sink has closed, cleanly or because of an error
+    pub fn is_closed(&self) -> bool {
+        matches!(self.status, SinkStatus::Closed | SinkStatus::Errored)
+    }
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+    /// Mutable access to the wrapped stream, e.g. to adjust socket options.
+    /// Reading from or writing to the stream directly will interleave with the sink's own
+    /// framing and corrupt any message that is partially read or written.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+    /// Swap in `stream`, e.g. after reconnecting, and reopen the sink. Unread and unwritten
+    /// bytes from the old stream are dropped along with the error that closed it, while the
+    /// buffers keep their capacity and settings and stats carry over.
+    pub fn reset(&mut self, stream: S) {
+        self.stream = stream;
+        self.reader.reset();
+        self.writer.reset();
+        self.status = SinkStatus::Open;
+        self.error = None;
+    }
+    /// Consume the sink, returning the wrapped stream along with any bytes that have been
+    /// read from it but not yet yielded as a message. Outbound bytes that have not been
+    /// written to the stream yet are discarded.
+    pub fn into_inner(self) -> (S, Vec<u8>) {
+        (self.stream, self.reader.into_buffer())
+    }
+    /// Like `into_inner`, but also returns the outbound bytes that have not been written yet.
+    /// Both buffers keep their allocations where possible, so once cleared they can be handed
+    /// to [`MessageSink::with_buffers`].
+    pub fn into_buffers(self) -> (S, Vec<u8>, Vec<u8>) {
+        let read = self.reader.into_buffer();
+        (self.stream, read, self.writer.buffer.into_vec())
+    }
+    /// Take the bytes that have been read from the stream but not yet yielded as a message.
+    /// When reading fails with `SinkError::Parse` the sink closes, but the bytes that failed
+    /// to parse stay buffered until taken, e.g. to hexdump them.
+    pub fn take_read_buffer(&mut self) -> Vec<u8> {
+        self.reader.take_buffer()
+    }
+    /// Yield at most `n` more messages, reading no further from the stream than the end of
+    /// the last of them.
+    pub fn take(self, n: usize) -> TakeSink<S, C> {
+        TakeSink::new(self, n)
+    }
+    /// Split the sink into halves that can be driven independently, e.g. from separate
+    /// tasks. Buffered bytes in either direction carry over to the matching half, and both
+    /// report to the observer. Keepalive pings go out while the writer is polled, see
+    /// [`SinkWriter::idle`].
+    pub fn split(self) -> (SinkReader<S, C>, SinkWriter<S, C>) {
+        let (read, write) = self.stream.split();
+        let (read_observed, write_observed) = self.observed.split();
+        (
+            SinkReader::new(read, self.reader, read_observed),
+            SinkWriter::new(write, self.writer, write_observed),
+        )
     }
 }
 
-impl<S> Future for MessageSink<S>
+type ReadFn<S, C, T> = fn(
+    &mut FrameReader<C>,
+    &mut S,
+    &mut Context<'_>,
+    FillFn<C, S>,
+) -> Poll<Result<Option<T>, SinkError>>;
+
+impl<S, C> MessageSink<S, C>
 where
     S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
 {
-    type Output = Result<Vec<u8>, SinkError>;
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let sink = self.get_mut();
-        let buffer = sink.write_buffer.as_ref();
-        match sink.status {
-            SinkStatus::Open => {}
+    /// Drive the sink, returning the next message once one is available. Resolves to
+    /// `Ok(None)` once when the sink finishes closing, either because `close` was called or
+    /// because the peer hung up, and to `Err(SinkError::Closed)` if polled after that.
+    fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Vec<u8>>, SinkError>> {
+        self.poll_with(cx, FrameReader::poll_next)
+    }
+
+    /// Every poll gives both directions a turn: the outbound buffer is written until it is
+    /// empty or the stream pushes back, and a stream that isn't writable never stops the
+    /// read that follows. Before returning `Pending` the task is registered for each thing
+    /// that can unblock it, i.e. the stream becoming readable, the stream becoming writable
+    /// while bytes are queued and new messages being queued, so neither direction waits
+    /// on the other.
+    fn poll_with<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        read: ReadFn<S, C, T>,
+    ) -> Poll<Result<Option<T>, SinkError>> {
+        if self.status == SinkStatus::Flushing {
+            ready!(self.poll_write(cx))?;
+            self.status = SinkStatus::Closing;
+        }
+        match self.status {
+            SinkStatus::Open | SinkStatus::Flushing => {}
             SinkStatus::Closing => {
-                let stream = Pin::new(&mut sink.stream);
+                let stream = Pin::new(&mut self.stream);
                 match stream.poll_close(cx) {
                     Poll::Pending => return Poll::Pending,
                     Poll::Ready(_) => {
-                        sink.status = SinkStatus::Closed;
-                        return Poll::Ready(Err(SinkError::Closed));
+                        self.status = SinkStatus::Closed;
+                        return Poll::Ready(Ok(None));
                     }
                 }
             }
             SinkStatus::Closed => {
                 return Poll::Ready(Err(SinkError::Closed));
             }
+            SinkStatus::Errored => return Poll::Ready(Err(self.errored())),
         }
-        let stream = Pin::new(&mut sink.stream);
-        match stream.poll_write(cx, buffer) {
-            Poll::Ready(Ok(length)) => {
-                sink.write_buffer.drain(0..length);
-            }
-            Poll::Ready(Err(e)) => {
-                sink.close();
-                return Poll::Ready(Err(SinkError::Write(e)));
+        if let Poll::Ready(Err(e)) = self.poll_write_buffer(cx) {
+            return Poll::Ready(Err(e));
+        }
+        self.writer.buffer.set_waker(cx);
+        match ready!(self.poll_read_frame(cx, read)) {
+            Ok(Some(message)) => Poll::Ready(Ok(Some(message))),
+            Ok(None) => {
+                // The peer hung up, there is no one left to flush to
+                self.status = SinkStatus::Closing;
+                self.poll_with(cx, read)
             }
-            Poll::Pending => {}
-        };
-        sink.write_buffer.set_waker(cx);
-        loop {
-            let stream = Pin::new(&mut sink.stream);
-            match stream.poll_read(cx, &mut sink.scratch) {
-                Poll::Ready(Ok(length)) => {
-                    if sink.read_buffer.len() + length > sink.limit {
-                        sink.close();
-                        return Poll::Ready(Err(SinkError::LimitExceeded));
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Make progress on the outbound side only: queue a keepalive if one is due, write
+    /// the buffer out unless it is being coalesced and shut the write half down once it is
+    /// closing. Resolves once there is nothing left to write.
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SinkError>> {
+        self.writer.poll_keepalive(cx);
+        if !self.writer.deferred() {
+            ready!(self.poll_write(cx))?;
+        }
+        let result = ready!(self.writer.poll_shutdown(&mut self.stream, cx));
+        Poll::Ready(result.map_err(|e| self.fail(e)))
+    }
+
+    /// Make progress on the inbound side only, reading until `read` yields a message.
+    /// Resolves to `Ok(None)` at EOF without closing the sink.
+    fn poll_read_frame<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        read: ReadFn<S, C, T>,
+    ) -> Poll<Result<Option<T>, SinkError>> {
+        let result = read(&mut self.reader, &mut self.stream, cx, self.fill);
+        self.observed.backlog(self.reader.buffered());
+        Poll::Ready(self.delivered(ready!(result)))
+    }
+
+    /// Write the outbound buffer to the stream until it is empty, closing the sink if that
+    /// fails. Does not flush the stream itself.
+    fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SinkError>> {
+        let result = self.writer.poll_flush(&mut self.stream, cx);
+        self.observed.written(self.writer.messages_written);
+        Poll::Ready(ready!(result).map_err(|e| self.fail(e)))
+    }
+
+    /// Count a delivered message against `max_messages`, or close the sink on error
+    fn delivered<T>(
+        &mut self,
+        result: Result<Option<T>, SinkError>,
+    ) -> Result<Option<T>, SinkError> {
+        match result {
+            Ok(Some(message)) => {
+                self.observed.read();
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining = remaining.saturating_sub(1);
+                    if *remaining == 0 {
+                        self.close();
                     }
-                    sink.read_buffer.extend(&sink.scratch[0..length]);
-                }
-                Poll::Ready(Err(e)) => {
-                    sink.close();
-                    return Poll::Ready(Err(SinkError::Read(e)));
-                }
-                Poll::Pending => {
-                    break;
-                }
-            };
-            match Frame::try_from(&mut sink.read_buffer) {
-                Ok(frame) => return Poll::Ready(Ok(frame.into_message())),
-                Err(ParseError::NotReady) => {}
-                Err(e) => {
-                    sink.close();
-                    return Poll::Ready(Err(SinkError::Parse(e)));
                 }
+                Ok(Some(message))
             }
+            result => result.map_err(|e| self.fail(e)),
         }
-        match Frame::try_from(&mut sink.read_buffer) {
-            Ok(frame) => return Poll::Ready(Ok(frame.into_message())),
-            Err(ParseError::NotReady) => {}
-            Err(e) => {
-                sink.close();
-                return Poll::Ready(Err(SinkError::Parse(e)));
-            }
+    }
+
+    /// Close the sink because of `error`, keeping a copy for `last_error`
+    fn fail(&mut self, error: SinkError) -> SinkError {
+        self.observed.error(&error);
+        self.error = Some(error.duplicate());
+        // Nothing queued will be written now
+        self.writer.acks.clear();
+        self.status = SinkStatus::Errored;
+        error
+    }
+
+    /// The error that closed the sink, again
+    fn errored(&self) -> SinkError {
+        self.error
+            .as_ref()
+            .map_or(SinkError::Closed, SinkError::duplicate)
+    }
+}
+
+/// Most buffered bytes shown by the `Debug` output
+const PREVIEW: usize = 32;
+
+/// Shows the status, limit and how much is buffered each way, with a hex preview of the
+/// start of the read buffer.
+impl<S, C> Debug for MessageSink<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buffer = &self.reader.buffer[..];
+        let mut preview: Vec<String> = buffer
+            .iter()
+            .take(PREVIEW)
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if buffer.len() > PREVIEW {
+            preview.push("..".to_string());
+        }
+        f.debug_struct("MessageSink")
+            .field("status", &self.status)
+            .field("read_buffer", &buffer.len())
+            .field("read_preview", &preview.join(" "))
+            .field("write_buffer", &self.writer.buffer.len())
+            .field("limit", &self.reader.limit)
+            .finish()
+    }
+}
+
+/// Resolves to the next message, like `recv`. Polling `&mut sink` is cancel-safe: every
+/// byte read from the stream is kept in the sink between polls, so a poll that loses a
+/// `select!` never drops part of a frame.
+impl<S, C> Future for MessageSink<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    type Output = Result<Option<Vec<u8>>, SinkError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().poll_message(cx)
+    }
+}
+
+impl<S, C> Stream for MessageSink<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    type Item = Result<Vec<u8>, SinkError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let sink = self.get_mut();
+        // The error has been yielded once already, end the stream there
+        if sink.status == SinkStatus::Errored {
+            return Poll::Ready(None);
+        }
+        sink.poll_message(cx).map(|message| match message {
+            Ok(message) => message.map(Ok),
+            Err(SinkError::Closed) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+}
+
+/// Messages still queued when the sink closes are never yielded, so the stream is over as
+/// soon as the sink is closed or errored
+impl<S, C> FusedStream for MessageSink<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    fn is_terminated(&self) -> bool {
+        matches!(self.status, SinkStatus::Closed | SinkStatus::Errored)
+    }
+}
+
+impl<S, C> Sink<Vec<u8>> for MessageSink<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    type Error = SinkError;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_write_ready(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.get_mut().write(item).map(drop)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        MessageSink::poll_flush(self, cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let sink = self.get_mut();
+        if matches!(sink.status, SinkStatus::Closed) {
+            return Poll::Ready(Ok(()));
+        }
+        if sink.status == SinkStatus::Errored {
+            // Release the stream, but keep reporting what went wrong
+            let _ = ready!(Pin::new(&mut sink.stream).poll_close(cx));
+            return Poll::Ready(Err(sink.errored()));
+        }
+        if matches!(sink.status, SinkStatus::Open | SinkStatus::Flushing) {
+            ready!(sink.poll_write(cx))?;
         }
-        Poll::Pending
+        ready!(Pin::new(&mut sink.stream).poll_close(cx)).map_err(SinkError::Write)?;
+        sink.status = SinkStatus::Closed;
+        Poll::Ready(Ok(()))
     }
 }
 
 #[cfg(test)]
 mod message_sink {
     use super::*;
-    use futures::{lock::Mutex, FutureExt};
-    use futures_ringbuf::RingBuffer;
+    use futures::{lock::Mutex, task::noop_waker, FutureExt, SinkExt, StreamExt};
+    use futures_ringbuf::{Endpoint, RingBuffer};
+    use proptest::{collection::vec, prelude::*};
     use rand::RngCore;
     use std::sync::Arc;
 
@@ -171,51 +1041,1979 @@ mod message_sink {
         bytes
     }
 
-    #[tokio::test]
-    async fn parse() {
-        let stream = RingBuffer::new(1024);
-        let mut sink = MessageSink::new(stream);
-        let message = random(128);
-        sink.write(message.clone()).unwrap();
-        let received = sink.await.unwrap();
-        assert_eq!(message, received);
+    /// A loopback stream that moves at most `chunk` bytes per read or write call
+    struct Chunked {
+        inner: RingBuffer<u8>,
+        chunk: usize,
+        reads: usize,
+        writes: usize,
     }
 
-    #[tokio::test]
-    async fn not_ready() {
-        let stream = RingBuffer::new(1024);
-        let sink = MessageSink::new(stream);
-        if sink.now_or_never().is_some() {
-            panic!("expected sink to not be ready");
+    impl Chunked {
+        fn new(capacity: usize, chunk: usize) -> Self {
+            Self {
+                inner: RingBuffer::new(capacity),
+                chunk,
+                reads: 0,
+                writes: 0,
+            }
         }
     }
 
-    #[tokio::test]
-    async fn parse_multiple() {
-        let messages = [random(128), random(128), random(128)];
-        let stream = RingBuffer::new(1024);
-        let mut sink = MessageSink::new(stream);
-        for message in messages.iter() {
-            sink.write(message.clone()).unwrap();
+    impl AsyncRead for Chunked {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let stream = self.get_mut();
+            stream.reads += 1;
+            let length = buf.len().min(stream.chunk);
+            Pin::new(&mut stream.inner).poll_read(cx, &mut buf[..length])
         }
-        let sink = Arc::new(Mutex::new(sink));
-        for message in messages {
-            let mut guard = sink.lock().await;
-            let received = (&mut *guard).await.unwrap();
-            assert_eq!(message, received);
+    }
+
+    impl AsyncWrite for Chunked {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let stream = self.get_mut();
+            stream.writes += 1;
+            let length = buf.len().min(stream.chunk);
+            Pin::new(&mut stream.inner).poll_write(cx, &buf[..length])
+        }
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_close(cx)
         }
     }
 
-    #[tokio::test]
-    async fn limit() {
-        let stream = RingBuffer::new(1024);
-        let mut sink = MessageSink::new(stream);
-        sink.limit(128);
-        sink.write(random(256)).unwrap();
-        match sink.await {
-            Err(SinkError::LimitExceeded) => {}
-            Err(e) => panic!("unexpected error {}", e),
-            Ok(_) => panic!("unexpected success"),
-        };
+    /// A stream whose writes always fail and whose reads never complete
+    struct Broken;
+
+    impl AsyncRead for Broken {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for Broken {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A stream that reads back `data` exactly one byte per successful poll, returning
+    /// `Pending` in between, and discards writes
+    struct Trickle {
+        data: std::collections::VecDeque<u8>,
+        ready: bool,
+    }
+
+    impl Trickle {
+        fn new(data: Vec<u8>) -> Self {
+            Self {
+                data: data.into(),
+                ready: true,
+            }
+        }
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let stream = self.get_mut();
+            if !stream.ready {
+                stream.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            stream.ready = false;
+            match stream.data.pop_front() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Poll::Ready(Ok(1))
+                }
+                None => Poll::Ready(Ok(0)),
+            }
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A stream that reads back `data` in chunks of the sizes in `chunks`, taken in turn,
+    /// returning `Pending` in between, and discards writes
+    struct Scripted {
+        data: std::collections::VecDeque<u8>,
+        chunks: Vec<usize>,
+        reads: usize,
+        ready: bool,
+    }
+
+    impl AsyncRead for Scripted {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let stream = self.get_mut();
+            stream.ready = !stream.ready;
+            if !stream.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let chunk = stream.chunks[stream.reads % stream.chunks.len()];
+            stream.reads += 1;
+            let length = chunk.min(buf.len()).min(stream.data.len());
+            for (byte, read) in buf.iter_mut().zip(stream.data.drain(..length)) {
+                *byte = read;
+            }
+            Poll::Ready(Ok(length))
+        }
+    }
+
+    impl AsyncWrite for Scripted {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A loopback stream whose every other read and write fails with `error`, for errors
+    /// that only mean "try again"
+    struct Flaky {
+        inner: RingBuffer<u8>,
+        error: std::io::ErrorKind,
+        reads: usize,
+        writes: usize,
+    }
+
+    impl Flaky {
+        fn new(error: std::io::ErrorKind) -> Self {
+            Self {
+                inner: RingBuffer::new(1024),
+                error,
+                reads: 0,
+                writes: 0,
+            }
+        }
+    }
+
+    impl AsyncRead for Flaky {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let stream = self.get_mut();
+            stream.reads += 1;
+            if stream.reads % 2 == 1 {
+                return Poll::Ready(Err(stream.error.into()));
+            }
+            Pin::new(&mut stream.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Flaky {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let stream = self.get_mut();
+            stream.writes += 1;
+            if stream.writes % 2 == 1 {
+                return Poll::Ready(Err(stream.error.into()));
+            }
+            Pin::new(&mut stream.inner).poll_write(cx, buf)
+        }
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_close(cx)
+        }
+    }
+
+    /// A stream that returns one whole datagram per read, and discards writes
+    struct Datagrams {
+        datagrams: std::collections::VecDeque<Vec<u8>>,
+        reads: usize,
+    }
+
+    impl AsyncRead for Datagrams {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let stream = self.get_mut();
+            stream.reads += 1;
+            let Some(datagram) = stream.datagrams.pop_front() else {
+                return Poll::Ready(Ok(0));
+            };
+            // Like UDP, whatever doesn't fit is dropped
+            let length = datagram.len().min(buf.len());
+            buf[..length].copy_from_slice(&datagram[..length]);
+            Poll::Ready(Ok(length))
+        }
+    }
+
+    impl AsyncWrite for Datagrams {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A loopback stream that accepts vectored writes, counting how many it has handled
+    struct Vectored {
+        inner: RingBuffer<u8>,
+        writes: usize,
+    }
+
+    impl AsyncRead for Vectored {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Vectored {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.poll_write_vectored(cx, &[std::io::IoSlice::new(buf)])
+        }
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            bufs: &[std::io::IoSlice<'_>],
+        ) -> Poll<std::io::Result<usize>> {
+            let stream = self.get_mut();
+            stream.writes += 1;
+            let buf: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+            Pin::new(&mut stream.inner).poll_write(cx, &buf)
+        }
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_close(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn parse() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        let message = random(128);
+        sink.write(message.clone()).unwrap();
+        let received = sink.await.unwrap().unwrap();
+        assert_eq!(message, received);
+    }
+
+    #[tokio::test]
+    async fn not_ready() {
+        let stream = RingBuffer::new(1024);
+        let sink = MessageSink::new(stream);
+        if sink.now_or_never().is_some() {
+            panic!("expected sink to not be ready");
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_multiple() {
+        let messages = [random(128), random(128), random(128)];
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        for message in messages.iter() {
+            sink.write(message.clone()).unwrap();
+        }
+        let sink = Arc::new(Mutex::new(sink));
+        for message in messages {
+            let mut guard = sink.lock().await;
+            let received = (&mut *guard).await.unwrap().unwrap();
+            assert_eq!(message, received);
+        }
+    }
+
+    #[tokio::test]
+    async fn limit() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.limit(128);
+        sink.write(random(256)).unwrap();
+        match sink.await {
+            Err(SinkError::Parse(ParseError::TooLarge {
+                declared: 256,
+                limit: 128,
+            })) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        };
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        let messages = [random(128), random(128), random(128)];
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        for message in messages.iter() {
+            sink.write(message.clone()).unwrap();
+        }
+        let mut i = 0;
+        while let Some(received) = sink.next().await {
+            assert_eq!(messages[i], received.unwrap());
+            i += 1;
+            if i == messages.len() {
+                break;
+            }
+        }
+        assert_eq!(i, 3);
+    }
+
+    #[tokio::test]
+    async fn stream_combinators() {
+        let messages = [random(128), random(64), random(128)];
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        for message in messages.iter() {
+            sink.write(message.clone()).unwrap();
+        }
+        let mut received = Vec::new();
+        (&mut sink)
+            .take(3)
+            .filter(|message| futures::future::ready(message.as_ref().unwrap().len() == 128))
+            .for_each(|message| {
+                received.push(message.unwrap());
+                futures::future::ready(())
+            })
+            .await;
+        assert_eq!(received, [messages[0].clone(), messages[2].clone()]);
+    }
+
+    #[tokio::test]
+    async fn stream_eof() {
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::close(&mut stream).await.unwrap();
+        let mut sink = MessageSink::new(stream);
+        assert!(sink.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fused() {
+        let message = random(16);
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.write(message.clone()).unwrap();
+        assert_eq!(message, sink.next().await.unwrap().unwrap());
+        sink.close();
+        assert!(!sink.is_terminated());
+        assert!(sink.next().await.is_none());
+        assert!(sink.is_terminated());
+        assert!(sink.next().await.is_none());
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.limit(8);
+        sink.write(random(16)).unwrap();
+        assert!(sink.next().await.unwrap().is_err());
+        assert!(sink.is_terminated());
+        assert!(sink.next().await.is_none());
+        let stream = futures::stream::select(sink, futures::stream::iter([Ok(message.clone())]));
+        assert_eq!(stream.collect::<Vec<_>>().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sink() {
+        let messages = [random(128), random(128), random(128)];
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.send(messages[0].clone()).await.unwrap();
+        let mut rest = futures::stream::iter(messages[1..].iter().cloned().map(Ok));
+        sink.send_all(&mut rest).await.unwrap();
+        for message in messages {
+            assert_eq!(message, sink.next().await.unwrap().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn sink_backpressure() {
+        let stream = RingBuffer::new(16);
+        let mut sink = MessageSink::new(stream);
+        sink.high_water_mark(8);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_ready());
+        Pin::new(&mut sink).start_send(random(32)).unwrap();
+        assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_pending());
+        let mut scratch = [0; 16];
+        let stream = Pin::new(&mut sink.stream);
+        assert!(matches!(
+            stream.poll_read(&mut cx, &mut scratch),
+            Poll::Ready(Ok(16))
+        ));
+        assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_ready());
+    }
+
+    #[tokio::test]
+    async fn sink_close() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        SinkExt::close(&mut sink).await.unwrap();
+        assert!(sink.next().await.is_none());
+        match sink.send(random(8)).await {
+            Err(SinkError::Closed) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[tokio::test]
+    async fn header_width() {
+        for width in [
+            HeaderWidth::U8,
+            HeaderWidth::U16,
+            HeaderWidth::U32,
+            HeaderWidth::U64,
+            HeaderWidth::Varint,
+            HeaderWidth::Packed,
+        ] {
+            let stream = RingBuffer::new(1024);
+            let mut sink = MessageSink::new(stream);
+            sink.header_width(width);
+            let message = random(200);
+            sink.write(message.clone()).unwrap();
+            let received = (&mut sink).await.unwrap().unwrap();
+            assert_eq!(message, received, "{:?}", width);
+        }
+    }
+
+    #[tokio::test]
+    async fn packed_type() {
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.header_width(HeaderWidth::Packed);
+        sink.write_to(255, random(8)).unwrap();
+        assert_eq!(sink.recv_from().await.unwrap().unwrap().0, 255);
+        match sink.write_to(256, random(8)) {
+            Err(SinkError::Parse(ParseError::Corrupt)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn header_one_byte_per_read() {
+        for width in [HeaderWidth::U32, HeaderWidth::Varint] {
+            let encoder = FrameEncoder::with_codec(FrameFormat {
+                width,
+                ..Default::default()
+            });
+            let messages = [random(300), Vec::new(), random(5)];
+            let stream: Vec<u8> = messages
+                .iter()
+                .flat_map(|message| encoder.encode(message).unwrap())
+                .collect();
+            let length = stream.len() as u64;
+            let mut sink = MessageSink::new(Trickle::new(stream));
+            sink.header_width(width);
+            // Every poll adds one byte of the header to the read buffer
+            let header = encoder.encode(&messages[0]).unwrap().len() - 300;
+            for read in 1..=header {
+                assert!(futures::poll!(Box::pin(sink.recv())).is_pending());
+                assert_eq!(sink.reader.buffer.len(), read);
+            }
+            for message in messages {
+                assert_eq!(Some(message), sink.recv().await.unwrap());
+            }
+            assert_eq!(None, sink.recv().await.unwrap());
+            assert_eq!(sink.stats().bytes_read, length);
+        }
+    }
+
+    proptest! {
+        /// However the stream splits its reads, including inside headers, every message
+        /// comes out whole and in order
+        #[test]
+        fn read_boundaries(
+            messages in vec(vec(any::<u8>(), 0..600), 0..12),
+            chunks in vec(1..64usize, 1..16),
+            varint in any::<bool>(),
+        ) {
+            let width = match varint {
+                true => HeaderWidth::Varint,
+                false => HeaderWidth::U32,
+            };
+            let encoder = FrameEncoder::with_codec(FrameFormat {
+                width,
+                ..Default::default()
+            });
+            let data: Vec<u8> = messages
+                .iter()
+                .flat_map(|message| encoder.encode(message).unwrap())
+                .collect();
+            let mut sink = MessageSink::new(Scripted {
+                data: data.into(),
+                chunks,
+                reads: 0,
+                ready: false,
+            });
+            sink.header_width(width);
+            let mut received = Vec::new();
+            loop {
+                // The stream wakes itself, so polling again is all it takes to make progress
+                match sink.recv().now_or_never() {
+                    Some(Ok(Some(message))) => received.push(message),
+                    Some(Ok(None)) => break,
+                    Some(Err(e)) => panic!("unexpected error {}", e),
+                    None => {}
+                }
+            }
+            prop_assert_eq!(received, messages);
+        }
+    }
+
+    #[tokio::test]
+    async fn endianness() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.endianness(Endianness::Big);
+        let message = random(300);
+        sink.write(message.clone()).unwrap();
+        let received = sink.await.unwrap().unwrap();
+        assert_eq!(message, received);
+    }
+
+    #[test]
+    fn current_limit() {
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        assert_eq!(sink.current_limit(), usize::MAX);
+        sink.limit(4096);
+        assert_eq!(sink.current_limit(), 4096);
+    }
+
+    #[tokio::test]
+    async fn limit_declared_length() {
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::write_all(&mut stream, &(1024 * 1024u32).to_le_bytes())
+            .await
+            .unwrap();
+        let mut sink = MessageSink::new(stream);
+        sink.limit(128);
+        match sink.now_or_never() {
+            Some(Err(SinkError::Parse(ParseError::TooLarge { declared, .. }))) => {
+                assert_eq!(declared, 1024 * 1024)
+            }
+            Some(Err(e)) => panic!("unexpected error {}", e),
+            Some(Ok(_)) => panic!("unexpected success"),
+            None => panic!("expected limit to be enforced before the body arrives"),
+        };
+    }
+
+    #[tokio::test]
+    async fn close_flushes() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let message = random(4096);
+        local.write(message.clone()).unwrap();
+        local.close();
+        let (closed, received) = futures::join!(local.recv(), remote.recv());
+        assert_eq!(None, closed.unwrap());
+        assert_eq!(Some(message), received.unwrap());
+        assert!(local.is_closed());
+        assert_eq!(None, remote.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let message = random(4096);
+        local.write(message.clone()).unwrap();
+        let (closed, received) = futures::join!(local.shutdown(), remote.recv());
+        closed.unwrap();
+        assert_eq!(Some(message), received.unwrap());
+        assert_eq!(None, remote.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reset() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut sink = MessageSink::new(local);
+        sink.write(random(32)).unwrap();
+        drop(remote);
+        match sink.recv().await {
+            Err(SinkError::Write(_)) | Ok(None) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        sink.write(random(16)).unwrap();
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        sink.reset(local);
+        assert_eq!(sink.status(), SinkStatus::Open);
+        assert!(sink.last_error().is_none());
+        assert!(sink.writer.buffer.is_empty());
+        let mut remote = MessageSink::new(remote);
+        let messages = [random(64), random(64)];
+        remote.send(messages[0].clone()).await.unwrap();
+        sink.send(messages[1].clone()).await.unwrap();
+        assert_eq!(Some(messages[0].clone()), sink.recv().await.unwrap());
+        assert_eq!(Some(messages[1].clone()), remote.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn debug_state() {
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::write_all(&mut stream, &[16, 0, 0, 0, 0xab])
+            .await
+            .unwrap();
+        let mut sink = MessageSink::new(stream);
+        sink.limit(1024);
+        let waker = noop_waker();
+        assert!(Pin::new(&mut sink)
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        sink.write(vec![0; 60]).unwrap();
+        let state = sink.debug_state();
+        assert!(state.contains("status: Open"), "{}", state);
+        assert!(state.contains("read_buffer: 5"), "{}", state);
+        assert!(state.contains("10 00 00 00 ab"), "{}", state);
+        assert!(state.contains("write_buffer: 64"), "{}", state);
+        assert!(state.contains("limit: 1024"), "{}", state);
+        assert_eq!(state, format!("{:?}", sink));
+    }
+
+    #[tokio::test]
+    async fn recv_chunk() {
+        let large = random(10 * 1024 * 1024);
+        let small = random(64);
+        let mut sink = MessageSink::new(RingBuffer::new(64 * 1024));
+        sink.chunk_threshold(Some(1024));
+        sink.write_all(vec![large.clone(), small.clone()]).unwrap();
+        let mut received = Vec::new();
+        let mut chunks = 0;
+        loop {
+            let chunk = sink.recv_chunk().await.unwrap().unwrap();
+            assert!(sink.reader.buffer.capacity() <= 128 * 1024);
+            received.extend(chunk.data);
+            chunks += 1;
+            if chunk.is_last {
+                break;
+            }
+        }
+        assert!(chunks > 1);
+        assert!(received == large);
+        let chunk = sink.recv_chunk().await.unwrap().unwrap();
+        assert_eq!(
+            chunk,
+            Chunk {
+                data: small,
+                is_last: true
+            }
+        );
+        assert_eq!(sink.stats().messages_read, 2);
+    }
+
+    #[tokio::test]
+    async fn recv_chunk_eof() {
+        let mut stream = RingBuffer::new(1024);
+        let mut framed = Vec::new();
+        FrameFormat::default()
+            .encode(&random(512), &mut framed)
+            .unwrap();
+        futures::AsyncWriteExt::write_all(&mut stream, &framed[..300])
+            .await
+            .unwrap();
+        futures::AsyncWriteExt::close(&mut stream).await.unwrap();
+        let mut sink = MessageSink::new(stream);
+        sink.chunk_threshold(Some(100));
+        let chunk = sink.recv_chunk().await.unwrap().unwrap();
+        assert_eq!(chunk.data, framed[4..300]);
+        assert!(!chunk.is_last);
+        match sink.recv_chunk().await {
+            Err(SinkError::UnexpectedEof) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn poll_write_ready() {
+        let (local, remote) = Endpoint::pair(64, 64);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        local.high_water_mark(128);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(local.poll_write_ready(&mut cx).is_ready());
+        let messages: Vec<Vec<u8>> = (0..4).map(|_| random(60)).collect();
+        local.write_all(messages.clone()).unwrap();
+        assert!(local.poll_write_ready(&mut cx).is_pending());
+        assert!(local.writer.buffer.len() > 128);
+        for message in messages {
+            assert_eq!(Some(message), remote.recv().await.unwrap());
+            if local.poll_write_ready(&mut cx).is_ready() {
+                break;
+            }
+        }
+        assert!(local.writer.buffer.len() <= 128);
+        poll_fn(|cx| local.poll_write_ready(cx)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn max_messages() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let messages = [random(16), random(16), random(16), random(16)];
+        remote.write_all(messages[..3].to_vec()).unwrap();
+        remote.flush().await.unwrap();
+        local.max_messages(2);
+        assert_eq!(Some(messages[0].clone()), local.recv().await.unwrap());
+        assert_eq!(Some(messages[1].clone()), local.try_recv().unwrap());
+        assert_eq!(local.status(), SinkStatus::Flushing);
+        local.write(messages[3].clone()).unwrap();
+        assert_eq!(None, local.try_recv().unwrap());
+        assert_eq!(None, local.recv().await.unwrap());
+        assert!(local.is_closed());
+        assert_eq!(Some(messages[3].clone()), remote.recv().await.unwrap());
+        assert_eq!(None, remote.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reads_not_starved() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        local.write_limit(usize::MAX);
+        let backlog: Vec<Vec<u8>> = (0..64).map(|_| random(1024)).collect();
+        local.write_all(backlog.clone()).unwrap();
+        let messages: Vec<Vec<u8>> = (0..8).map(|_| random(64)).collect();
+        let peer = async {
+            for message in &messages {
+                remote.send(message.clone()).await.unwrap();
+            }
+        };
+        let reads = async {
+            let mut received = Vec::new();
+            for _ in 0..messages.len() {
+                received.push(local.recv().await.unwrap().unwrap());
+            }
+            received
+        };
+        let ((), received) = tokio::time::timeout(Duration::from_secs(5), async {
+            futures::join!(peer, reads)
+        })
+        .await
+        .expect("reads starved behind the write backlog");
+        assert_eq!(received, messages);
+        assert!(!local.writer.buffer.is_empty());
+        let (flushed, drained) = futures::join!(local.flush(), async {
+            let mut drained = Vec::new();
+            for _ in 0..backlog.len() {
+                drained.push(remote.recv().await.unwrap().unwrap());
+            }
+            drained
+        });
+        flushed.unwrap();
+        assert_eq!(drained, backlog);
+    }
+
+    #[tokio::test]
+    async fn write_slice() {
+        for length in [0, 100, 5000] {
+            let message = random(length);
+            let mut owned = MessageSink::new(RingBuffer::new(8192));
+            let mut borrowed = MessageSink::new(RingBuffer::new(8192));
+            owned.checksum(true);
+            borrowed.checksum(true);
+            owned.write(message.clone()).unwrap();
+            borrowed.write_slice(&message).unwrap();
+            let (_, _, queued) = owned.into_buffers();
+            let (borrowed, _, sliced) = borrowed.into_buffers();
+            assert_eq!(queued, sliced);
+            let mut sink = MessageSink::new(borrowed);
+            sink.checksum(true);
+            sink.write_slice(&message).unwrap();
+            assert_eq!(Some(message), sink.recv().await.unwrap());
+            assert_eq!(sink.stats().messages_written, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn write_len() {
+        let mut sink = MessageSink::new(RingBuffer::new(8192));
+        for length in [0, 100, 5000] {
+            assert_eq!(sink.write(random(length)).unwrap(), length + 4);
+            assert_eq!(sink.write_slice(&random(length)).unwrap(), length + 4);
+        }
+        assert_eq!(sink.write_all(vec![random(10), random(20)]).unwrap(), 38);
+        sink.header_width(HeaderWidth::U16);
+        sink.checksum(true);
+        assert_eq!(sink.write(random(100)).unwrap(), 106);
+        assert_eq!(sink.writer.buffer.len(), 2 * (4 + 104 + 5004) + 38 + 106);
+    }
+
+    #[tokio::test]
+    async fn write_acked() {
+        let (local, remote) = Endpoint::pair(32, 32);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let message = random(64);
+        let mut ack = local.write_acked(message.clone()).unwrap();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut local).poll_flush(&mut cx).is_pending());
+        assert!(local.stats().bytes_written > 0);
+        assert!(Pin::new(&mut ack).poll(&mut cx).is_pending());
+        let (flushed, received) = futures::join!(local.flush(), remote.recv());
+        flushed.unwrap();
+        assert_eq!(Some(message), received.unwrap());
+        ack.await.unwrap();
+        let ack = local.write_acked(random(8)).unwrap();
+        drop(local);
+        match ack.await {
+            Err(SinkError::Closed) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn graceful_close() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.close();
+        match (&mut sink).await {
+            Ok(None) => {}
+            Ok(Some(_)) => panic!("unexpected message"),
+            Err(e) => panic!("unexpected error {}", e),
+        }
+        match (&mut sink).await {
+            Err(SinkError::Closed) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        assert!(sink.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn peer_hangup() {
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::close(&mut stream).await.unwrap();
+        let sink = MessageSink::new(stream);
+        match sink.await {
+            Ok(None) => {}
+            Ok(Some(_)) => panic!("unexpected message"),
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn into_inner() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        let message = random(128);
+        let extra = random(16);
+        sink.send(message.clone()).await.unwrap();
+        futures::AsyncWriteExt::write_all(&mut sink.stream, &extra)
+            .await
+            .unwrap();
+        let received = (&mut sink).await.unwrap().unwrap();
+        assert_eq!(message, received);
+        let (_stream, remaining) = sink.into_inner();
+        assert_eq!(extra, remaining);
+    }
+
+    #[tokio::test]
+    async fn get_ref() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        assert_eq!(sink.get_ref().capacity(), 1024);
+        let message = random(128);
+        sink.send(message.clone()).await.unwrap();
+        assert_eq!(sink.get_ref().len(), 132);
+        assert!(sink.get_mut().remaining() < 1024);
+    }
+
+    #[tokio::test]
+    async fn write_all_in_one_poll() {
+        let messages = [random(128), random(128), random(128)];
+        let stream = Chunked::new(1024, 64);
+        let mut sink = MessageSink::new(stream);
+        for message in messages.iter() {
+            sink.write(message.clone()).unwrap();
+        }
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        match Pin::new(&mut sink).poll(&mut cx) {
+            Poll::Ready(Ok(Some(received))) => assert_eq!(messages[0], received),
+            _ => panic!("expected a message"),
+        }
+        assert!(sink.writer.buffer.is_empty());
+        assert_eq!(sink.stream.writes, 7);
+    }
+
+    #[test]
+    fn partial_writes() {
+        let stream = Chunked::new(1024, 8);
+        let mut sink = MessageSink::new(stream);
+        sink.write(random(96)).unwrap();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(sink.poll_drain(&mut cx), Poll::Ready(Ok(()))));
+        assert!(sink.writer.buffer.is_empty());
+        assert_eq!(sink.stream.writes, 13);
+    }
+
+    #[test]
+    fn write_zero() {
+        let mut full = [0; 2];
+        let mut sink = MessageSink::new(futures::io::Cursor::new(&mut full[..]));
+        sink.write(random(8)).unwrap();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        match sink.poll_drain(&mut cx) {
+            Poll::Ready(Err(SinkError::Write(e))) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::WriteZero)
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn would_block() {
+        use std::io::ErrorKind::{Interrupted, WouldBlock};
+        for error in [WouldBlock, Interrupted] {
+            let mut sink = MessageSink::new(Flaky::new(error));
+            let messages = [random(64), random(16)];
+            for message in &messages {
+                sink.write(message.clone()).unwrap();
+                assert_eq!(Some(message.clone()), sink.recv().await.unwrap());
+            }
+            assert!(sink.stream.reads > 2 && sink.stream.writes > 2);
+            assert_eq!(sink.status(), SinkStatus::Open);
+        }
+    }
+
+    #[test]
+    fn write_interrupted() {
+        let mut sink = MessageSink::new(Flaky::new(std::io::ErrorKind::Interrupted));
+        sink.write(random(64)).unwrap();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(sink.poll_drain(&mut cx), Poll::Ready(Ok(()))));
+        assert_eq!(sink.stream.writes, 2);
+        assert_eq!(sink.stats().messages_written, 1);
+    }
+
+    #[tokio::test]
+    async fn coalesce() {
+        let mut sink = MessageSink::new(Chunked::new(4096, usize::MAX));
+        sink.coalesce(Some(1024));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let messages: Vec<Vec<u8>> = (0..100).map(|_| random(8)).collect();
+        // Polling reads back whatever has been written so far
+        let mut received = Vec::new();
+        for message in &messages {
+            sink.write(message.clone()).unwrap();
+            if let Poll::Ready(result) = Pin::new(&mut sink).poll(&mut cx) {
+                received.push(result.unwrap().unwrap());
+            }
+        }
+        sink.flush().await.unwrap();
+        assert!(sink.stream.writes <= 4, "{} writes", sink.stream.writes);
+        while received.len() < messages.len() {
+            received.push(sink.recv().await.unwrap().unwrap());
+        }
+        assert_eq!(received, messages);
+    }
+
+    #[tokio::test]
+    async fn read_chunk_size() {
+        let message = random(16 * 1024);
+        let stream = Chunked::new(32 * 1024, usize::MAX);
+        let mut sink = MessageSink::new(stream);
+        sink.read_chunk_size(64 * 1024);
+        sink.write(message.clone()).unwrap();
+        let received = (&mut sink).await.unwrap().unwrap();
+        assert_eq!(message, received);
+        assert_eq!(sink.stream.reads, 1);
+    }
+
+    #[tokio::test]
+    async fn read_chunk_size_fewer_reads() {
+        let message = random(64 * 1024);
+        let mut reads = Vec::new();
+        for chunk in [1024, 64 * 1024] {
+            let stream = Chunked::new(128 * 1024, usize::MAX);
+            let mut sink = MessageSink::new(stream);
+            sink.read_chunk_size(chunk);
+            sink.write(message.clone()).unwrap();
+            let received = (&mut sink).await.unwrap().unwrap();
+            assert_eq!(message, received);
+            reads.push(sink.stream.reads);
+        }
+        assert!(reads[1] * 16 < reads[0], "reads: {:?}", reads);
+    }
+
+    #[tokio::test]
+    async fn shrink_threshold() {
+        let message = random(256 * 1024);
+        let mut sink = MessageSink::new(Chunked::new(512 * 1024, usize::MAX));
+        sink.write(message.clone()).unwrap();
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+        assert!(sink.reader.buffer.capacity() <= 8 * 1024);
+
+        let message = random(256 * 1024);
+        let mut sink = MessageSink::new(Chunked::new(512 * 1024, usize::MAX));
+        sink.shrink_threshold(None);
+        sink.write(message.clone()).unwrap();
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+        #[cfg(not(feature = "bytes"))]
+        assert!(sink.reader.buffer.capacity() >= 256 * 1024);
+        sink.compact_read_buffer();
+        assert!(sink.reader.buffer.capacity() <= 8 * 1024);
+    }
+
+    #[tokio::test]
+    async fn recv() {
+        let messages = [random(128), random(128)];
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        for message in messages.iter() {
+            sink.send(message.clone()).await.unwrap();
+        }
+        futures::AsyncWriteExt::close(sink.get_mut()).await.unwrap();
+        for message in messages {
+            assert_eq!(Some(message), sink.recv().await.unwrap());
+        }
+        assert_eq!(None, sink.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn recv_into() {
+        let messages: Vec<_> = (0..100).map(|i| random(i % 64)).collect();
+        let mut sink = MessageSink::new(RingBuffer::new(8 * 1024));
+        sink.write_all(messages.clone()).unwrap();
+        sink.flush().await.unwrap();
+        futures::AsyncWriteExt::close(sink.get_mut()).await.unwrap();
+        let mut out = Vec::with_capacity(64);
+        let allocation = out.as_ptr();
+        for message in messages {
+            assert_eq!(Some(message.len()), sink.recv_into(&mut out).await.unwrap());
+            assert_eq!(out, message);
+            assert_eq!(out.as_ptr(), allocation);
+        }
+        assert_eq!(sink.stats().messages_read, 100);
+        assert_eq!(None, sink.recv_into(&mut out).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn peek() {
+        let message = random(128);
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        let frame: Vec<u8> = Frame::new(message.clone()).try_into().unwrap();
+        sink.reader.buffer.extend(frame);
+        assert_eq!(Some(&message[..]), sink.peek().unwrap());
+        assert_eq!(Some(&message[..]), sink.peek().unwrap());
+        assert_eq!(sink.stats().messages_read, 0);
+        assert_eq!(Some(message), sink.try_recv().unwrap());
+        assert_eq!(None, sink.peek().unwrap());
+        // Nothing is yielded once the sink is closing, so there is nothing to peek at either
+        let frame: Vec<u8> = Frame::new(random(8)).try_into().unwrap();
+        sink.reader.buffer.extend(frame);
+        sink.close();
+        assert_eq!(None, sink.peek().unwrap());
+        assert_eq!(None, sink.try_recv().unwrap());
+    }
+
+    #[tokio::test]
+    async fn try_recv() {
+        let messages = [random(128), random(128)];
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        assert_eq!(None, sink.try_recv().unwrap());
+        for message in messages.iter() {
+            let frame: Vec<u8> = Frame::new(message.clone()).try_into().unwrap();
+            sink.reader.buffer.extend(frame);
+        }
+        for message in messages {
+            assert_eq!(Some(message), sink.try_recv().unwrap());
+        }
+        assert_eq!(None, sink.try_recv().unwrap());
+        assert_eq!(sink.get_ref().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        assert_eq!(sink.status(), SinkStatus::Open);
+        sink.close();
+        assert_eq!(sink.status(), SinkStatus::Flushing);
+        assert!(!sink.is_closed());
+        assert_eq!(None, sink.recv().await.unwrap());
+        assert_eq!(sink.status(), SinkStatus::Closed);
+        assert!(sink.is_closed());
+    }
+
+    #[tokio::test]
+    async fn status_after_write_error() {
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::close(&mut stream).await.unwrap();
+        let mut sink = MessageSink::new(stream);
+        sink.write(random(128)).unwrap();
+        match sink.recv().await {
+            Err(SinkError::Write(_)) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        assert_eq!(sink.status(), SinkStatus::Errored);
+        assert!(matches!(sink.recv().await, Err(SinkError::Write(_))));
+        assert!(sink.is_closed());
+    }
+
+    #[tokio::test]
+    async fn errored() {
+        let format = FrameFormat {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut framed = Vec::new();
+        format.encode(&random(16), &mut framed).unwrap();
+        *framed.last_mut().unwrap() ^= 1;
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.checksum(true);
+        sink.write_raw(framed).unwrap();
+        for _ in 0..2 {
+            match sink.recv().await {
+                Err(SinkError::Parse(ParseError::ChecksumMismatch)) => {}
+                result => panic!("unexpected result {:?}", result),
+            }
+            assert_eq!(sink.status(), SinkStatus::Errored);
+        }
+        assert!(sink.is_closed());
+        assert!(sink.next().await.is_none());
+        assert!(matches!(
+            poll_fn(|cx| sink.poll_write_ready(cx)).await,
+            Err(SinkError::Parse(ParseError::ChecksumMismatch))
+        ));
+        match SinkExt::close(&mut sink).await {
+            Err(SinkError::Parse(ParseError::ChecksumMismatch)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(sink.status(), SinkStatus::Errored);
+        match sink.recv().await {
+            Err(SinkError::Parse(ParseError::ChecksumMismatch)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let (a, b) = futures::join!(local.handshake(64), remote.handshake(128));
+        assert_eq!((a.unwrap(), b.unwrap()), (64, 64));
+        assert!(matches!(
+            remote.write(random(65)),
+            Err(SinkError::LimitExceeded)
+        ));
+        let message = random(64);
+        remote.write(message.clone()).unwrap();
+        remote.flush().await.unwrap();
+        assert_eq!(Some(message), local.recv().await.unwrap());
+        // A peer that ignores the agreement is cut off
+        remote.writer.max_message = usize::MAX;
+        remote.write(random(65)).unwrap();
+        remote.flush().await.unwrap();
+        assert!(matches!(
+            local.recv().await,
+            Err(SinkError::Parse(ParseError::TooLarge {
+                declared: 65,
+                limit: 64
+            }))
+        ));
+
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        remote.write(vec![0; 4]).unwrap();
+        remote.flush().await.unwrap();
+        assert!(matches!(
+            local.handshake(64).await,
+            Err(SinkError::Parse(ParseError::Corrupt))
+        ));
+    }
+
+    #[tokio::test]
+    async fn datagram() {
+        let datagrams = vec![random(100), random(3000), random(1)];
+        let mut sink = MessageSink::new(Datagrams {
+            datagrams: datagrams.clone().into(),
+            reads: 0,
+        });
+        sink.datagram(true);
+        for (read, datagram) in datagrams.into_iter().enumerate() {
+            assert_eq!(Some(datagram), sink.recv().await.unwrap());
+            assert_eq!(sink.get_ref().reads, read + 1);
+        }
+        assert_eq!(None, sink.recv().await.unwrap());
+
+        let mut sink = MessageSink::new(Datagrams {
+            datagrams: vec![random(1000), random(2000)].into(),
+            reads: 0,
+        });
+        sink.datagram(true);
+        sink.limit(1000);
+        assert_eq!(1000, sink.recv().await.unwrap().unwrap().len());
+        match sink.recv().await {
+            Err(SinkError::Parse(ParseError::TooLarge {
+                declared: 2000,
+                limit: 1000,
+            })) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // Without a limit, a datagram filling the whole read can't be told apart from one
+        // the stream cut short
+        let mut sink = MessageSink::new(Datagrams {
+            datagrams: vec![random(64 * 1024), random(128 * 1024)].into(),
+            reads: 0,
+        });
+        sink.datagram(true);
+        assert_eq!(64 * 1024, sink.recv().await.unwrap().unwrap().len());
+        match sink.recv().await {
+            Err(SinkError::Read(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            result => panic!("unexpected result {:?}", result.map(|m| m.map(|m| m.len()))),
+        }
+    }
+
+    #[tokio::test]
+    async fn last_error() {
+        let mut sink = MessageSink::new(Broken);
+        assert!(sink.last_error().is_none());
+        sink.write(random(8)).unwrap();
+        match sink.recv().await {
+            Err(SinkError::Write(e)) => assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(matches!(sink.recv().await, Err(SinkError::Write(_))));
+        let error = sink.last_error().unwrap();
+        assert_eq!(
+            error.kind(),
+            SinkErrorKind::Write(std::io::ErrorKind::BrokenPipe)
+        );
+    }
+
+    #[test]
+    fn error_kind() {
+        let error = SinkError::Parse(ParseError::Corrupt);
+        let consumers = vec![error.kind(); 2];
+        for kind in consumers {
+            assert_eq!(kind, SinkErrorKind::Parse(ParseError::Corrupt));
+        }
+        let error = SinkError::write(std::io::ErrorKind::BrokenPipe.into());
+        assert_eq!(
+            error.kind(),
+            SinkErrorKind::Write(std::io::ErrorKind::BrokenPipe)
+        );
+        assert_eq!(
+            SinkError::codec(ParseError::Corrupt).kind(),
+            SinkErrorKind::Codec
+        );
+    }
+
+    #[test]
+    fn error_from() {
+        fn decode(mut buffer: Vec<u8>) -> Result<Vec<u8>, SinkError> {
+            let frame = Frame::try_from(&mut buffer, &FrameFormat::default(), usize::MAX)?;
+            Ok(frame.into_message())
+        }
+        fn send(stream: &mut Vec<u8>, message: &[u8]) -> Result<(), SinkError> {
+            std::io::Write::write_all(stream, message).map_err(SinkError::write)
+        }
+        let mut buffer = Vec::new();
+        send(&mut buffer, &[2, 0, 0, 0, 1, 2]).unwrap();
+        assert_eq!(decode(buffer).unwrap(), vec![1, 2]);
+        match decode(vec![2, 0, 0, 0, 1]) {
+            Err(SinkError::Parse(e)) => assert_eq!(e, ParseError::NotReady),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        let error = SinkError::read(std::io::ErrorKind::UnexpectedEof.into());
+        assert!(matches!(error, SinkError::Read(_)));
+    }
+
+    #[tokio::test]
+    async fn error_source() {
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::close(&mut stream).await.unwrap();
+        let mut sink = MessageSink::new(stream);
+        sink.write(random(128)).unwrap();
+        let error = sink.recv().await.unwrap_err();
+        let source = error.source().unwrap();
+        let io = source.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io.kind(), std::io::ErrorKind::NotConnected);
+    }
+
+    #[tokio::test]
+    async fn write_limit() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.write_limit(300);
+        sink.write(random(128)).unwrap();
+        sink.write(random(128)).unwrap();
+        match sink.write(random(128)) {
+            Err(SinkError::WriteBufferFull) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut sink).poll(&mut cx).is_ready());
+        sink.write(random(128)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        assert_eq!(sink.stats(), SinkStats::default());
+        for _ in 0..3 {
+            sink.write(random(128)).unwrap();
+        }
+        for _ in 0..3 {
+            sink.recv().await.unwrap().unwrap();
+        }
+        let expected = SinkStats {
+            bytes_read: 3 * 132,
+            bytes_written: 3 * 132,
+            messages_read: 3,
+            messages_written: 3,
+        };
+        assert_eq!(sink.stats(), expected);
+    }
+
+    #[tokio::test]
+    async fn stats_partial_write() {
+        let stream = Chunked::new(1024, 100);
+        let mut sink = MessageSink::new(stream);
+        sink.write(random(128)).unwrap();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let _ = sink.writer.poll_write(&mut sink.stream, &mut cx);
+        assert_eq!(sink.stats().bytes_written, 100);
+        assert_eq!(sink.stats().messages_written, 0);
+        let _ = sink.writer.poll_write(&mut sink.stream, &mut cx);
+        assert_eq!(sink.stats().bytes_written, 132);
+        assert_eq!(sink.stats().messages_written, 1);
+    }
+
+    #[tokio::test]
+    async fn checksum() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.checksum(true);
+        let message = random(128);
+        sink.write(message.clone()).unwrap();
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn magic() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.magic(b"MSNK", 1);
+        let message = random(128);
+        sink.write(message.clone()).unwrap();
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn recv_cancelled() {
+        let message = random(64);
+        let frame: Vec<u8> = Frame::new(message.clone()).try_into().unwrap();
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        futures::AsyncWriteExt::write_all(sink.get_mut(), &frame[..30])
+            .await
+            .unwrap();
+        assert!(sink.recv().now_or_never().is_none());
+        assert!((&mut sink).now_or_never().is_none());
+        futures::select! {
+            _ = sink.recv().fuse() => panic!("unexpected message"),
+            _ = futures::future::ready(()).fuse() => {}
+        }
+        futures::AsyncWriteExt::write_all(sink.get_mut(), &frame[30..])
+            .await
+            .unwrap();
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn write_raw() {
+        let message = random(64);
+        let mut upstream = MessageSink::new(RingBuffer::new(1024));
+        upstream.write(message.clone()).unwrap();
+        upstream.flush().await.unwrap();
+        let (mut stream, _) = upstream.into_inner();
+        let mut framed = vec![0; framed_len(64)];
+        futures::AsyncReadExt::read_exact(&mut stream, &mut framed)
+            .await
+            .unwrap();
+        let mut downstream = MessageSink::new(RingBuffer::new(1024));
+        downstream.write_raw(framed).unwrap();
+        downstream.write(random(8)).unwrap();
+        assert_eq!(Some(message), downstream.recv().await.unwrap());
+        assert_eq!(downstream.recv().await.unwrap().unwrap().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn length_semantics() {
+        for length in [LengthSemantics::PayloadOnly, LengthSemantics::Inclusive] {
+            let mut sink = MessageSink::new(RingBuffer::new(1024));
+            sink.length_semantics(length);
+            let messages = [random(16), Vec::new(), random(300)];
+            sink.write_all(messages.to_vec()).unwrap();
+            for message in messages {
+                assert_eq!(Some(message), sink.recv().await.unwrap());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn eof() {
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        let message = random(16);
+        sink.write(message.clone()).unwrap();
+        sink.flush().await.unwrap();
+        futures::AsyncWriteExt::close(sink.get_mut()).await.unwrap();
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+        assert_eq!(None, sink.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unexpected_eof() {
+        let mut stream = RingBuffer::new(1024);
+        let frame: Vec<u8> = Frame::new(random(16)).try_into().unwrap();
+        futures::AsyncWriteExt::write_all(&mut stream, &frame[..10])
+            .await
+            .unwrap();
+        futures::AsyncWriteExt::close(&mut stream).await.unwrap();
+        let mut sink = MessageSink::new(stream);
+        match sink.recv().await {
+            Err(SinkError::UnexpectedEof) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(sink.take_read_buffer(), frame[..10]);
+    }
+
+    #[tokio::test]
+    async fn take_read_buffer() {
+        let mut stream = RingBuffer::new(1024);
+        let garbage = b"HTTP/1.1 400 Bad Request\r\n".to_vec();
+        futures::AsyncWriteExt::write_all(&mut stream, &garbage)
+            .await
+            .unwrap();
+        let mut sink = MessageSink::new(stream);
+        sink.magic(b"MSNK", 1);
+        match sink.recv().await {
+            Err(SinkError::Parse(ParseError::Corrupt)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(sink.take_read_buffer(), garbage);
+        assert!(sink.take_read_buffer().is_empty());
+    }
+
+    #[tokio::test]
+    async fn from_buf_read() {
+        let messages = vec![random(10), random(30), random(200), random(0), random(40)];
+        let mut plain = MessageSink::new(RingBuffer::new(1024));
+        let stream = futures::io::BufReader::with_capacity(64, RingBuffer::new(1024));
+        let mut buffered = MessageSink::from_buf_read(stream);
+        plain.write_all(messages.clone()).unwrap();
+        buffered.write_all(messages.clone()).unwrap();
+        plain.flush().await.unwrap();
+        buffered.flush().await.unwrap();
+        assert_eq!(Some(messages[0].clone()), buffered.recv().await.unwrap());
+        assert_eq!(buffered.reader.buffer.capacity(), 0);
+        let mut received = vec![messages[0].clone()];
+        for _ in 1..messages.len() {
+            received.push(buffered.recv().await.unwrap().unwrap());
+        }
+        assert_eq!(received, messages);
+        for message in received {
+            assert_eq!(Some(message), plain.recv().await.unwrap());
+        }
+        assert_eq!(buffered.stats(), plain.stats());
+    }
+
+    #[tokio::test]
+    async fn with_capacity() {
+        let mut sink = MessageSink::with_capacity(RingBuffer::new(128 * 1024), 64 * 1024, 1024);
+        assert!(sink.reader.buffer.capacity() >= 64 * 1024);
+        let message = random(48 * 1024);
+        sink.send(message.clone()).await.unwrap();
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+        // Compacting keeps the requested capacity
+        sink.compact_read_buffer();
+        #[cfg(not(feature = "bytes"))]
+        assert!(sink.reader.buffer.capacity() >= 64 * 1024);
+        let (_, _, write_buf) =
+            MessageSink::with_capacity(RingBuffer::new(1024), 0, 1024).into_buffers();
+        assert!(write_buf.capacity() >= 1024);
+    }
+
+    #[tokio::test]
+    async fn with_buffers() {
+        let messages = vec![random(100), random(500), random(10)];
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        for message in &messages {
+            sink.send(message.clone()).await.unwrap();
+            assert_eq!(Some(message.clone()), sink.recv().await.unwrap());
+        }
+        let (stream, mut read_buf, mut write_buf) = sink.into_buffers();
+        assert!(read_buf.is_empty());
+        assert!(write_buf.is_empty());
+        read_buf.extend(b"stale");
+        write_buf.extend(b"stale");
+        let mut sink = MessageSink::with_buffers(stream, read_buf, write_buf);
+        for message in &messages {
+            sink.send(message.clone()).await.unwrap();
+            assert_eq!(Some(message.clone()), sink.recv().await.unwrap());
+        }
+        sink.write(messages[0].clone()).unwrap();
+        let (_, read_buf, write_buf) = sink.into_buffers();
+        assert!(read_buf.is_empty());
+        let mut framed = Vec::new();
+        FrameFormat::default()
+            .encode(&messages[0], &mut framed)
+            .unwrap();
+        assert_eq!(write_buf, framed);
+    }
+
+    #[tokio::test]
+    async fn varint() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.header_width(HeaderWidth::Varint);
+        let message = random(100);
+        sink.write(message.clone()).unwrap();
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+        assert_eq!(sink.stats().bytes_read, 101);
+    }
+
+    #[tokio::test]
+    async fn write_all() {
+        let messages: Vec<Vec<u8>> = (0..5).map(|_| random(128)).collect();
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.write_all(messages.clone()).unwrap();
+        for message in messages {
+            assert_eq!(Some(message), sink.recv().await.unwrap());
+        }
+        assert_eq!(sink.stats().messages_written, 5);
+    }
+
+    #[tokio::test]
+    async fn write_all_over_limit() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.write_limit(300);
+        match sink.write_all(vec![random(128), random(128), random(128)]) {
+            Err(SinkError::WriteBufferFull) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        assert!(sink.writer.buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn flush() {
+        let message = random(32 * 1024);
+        let stream = Chunked::new(64 * 1024, 1024);
+        let mut sink = MessageSink::new(stream);
+        sink.write(message.clone()).unwrap();
+        sink.flush().await.unwrap();
+        assert!(sink.writer.buffer.is_empty());
+        assert_eq!(sink.stream.reads, 0);
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+    }
+
+    #[cfg(feature = "bytes")]
+    #[tokio::test]
+    async fn bytes() {
+        let messages = [random(128), random(64)];
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.write_bytes(Bytes::from(messages[0].clone())).unwrap();
+        sink.write(messages[1].clone()).unwrap();
+        assert_eq!(messages[0], sink.recv_bytes().await.unwrap().unwrap());
+        assert_eq!(Some(messages[1].clone()), sink.recv().await.unwrap());
+        sink.write_bytes(Bytes::from(messages[1].clone())).unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(None, sink.try_recv_bytes().unwrap());
+        sink.recv_bytes().await.unwrap();
+        assert_eq!(sink.stats().messages_read, 3);
+    }
+
+    #[tokio::test]
+    async fn write_vectored() {
+        let messages = [random(16 * 1024), random(16 * 1024)];
+        let stream = Vectored {
+            inner: RingBuffer::new(64 * 1024),
+            writes: 0,
+        };
+        let mut sink = MessageSink::new(stream);
+        sink.write_all(messages.to_vec()).unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(sink.stream.writes, 1);
+        for message in messages {
+            assert_eq!(Some(message), sink.recv().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn ready_queue() {
+        let messages: Vec<Vec<u8>> = (0..5).map(|_| random(128)).collect();
+        let mut stream = Chunked::new(1024, usize::MAX);
+        for message in messages.iter() {
+            let frame: Vec<u8> = Frame::new(message.clone()).try_into().unwrap();
+            futures::AsyncWriteExt::write_all(&mut stream, &frame)
+                .await
+                .unwrap();
+        }
+        let mut sink = MessageSink::new(stream);
+        assert_eq!(Some(messages[0].clone()), sink.recv().await.unwrap());
+        assert_eq!(sink.stream.reads, 1);
+        assert_eq!(sink.reader.ready.len(), 4);
+        for message in messages[1..].iter() {
+            assert_eq!(Some(message.clone()), sink.recv().await.unwrap());
+        }
+        assert_eq!(sink.stream.reads, 1);
+        assert_eq!(sink.stats().messages_read, 5);
+    }
+
+    #[tokio::test]
+    async fn poll_flush() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let messages = [random(32), random(32)];
+        remote.write(messages[0].clone()).unwrap();
+        remote.flush().await.unwrap();
+        local.write(messages[1].clone()).unwrap();
+        poll_fn(|cx| Pin::new(&mut local).poll_flush(cx))
+            .await
+            .unwrap();
+        assert!(local.writer.buffer.is_empty());
+        assert_eq!(local.stats().bytes_read, 0);
+        assert_eq!(Some(messages[1].clone()), remote.recv().await.unwrap());
+        assert_eq!(Some(messages[0].clone()), local.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn poll_peer_closed() {
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        assert!(poll_fn(|cx| Poll::Ready(sink.poll_peer_closed(cx)))
+            .await
+            .is_pending());
+        let message = random(64);
+        sink.write(message.clone()).unwrap();
+        sink.flush().await.unwrap();
+        futures::AsyncWriteExt::close(sink.get_mut()).await.unwrap();
+        assert!(!poll_fn(|cx| sink.poll_peer_closed(cx)).await);
+        assert!(poll_fn(|cx| sink.poll_peer_closed(cx)).await);
+        // Probing consumed nothing
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+        assert_eq!(None, sink.recv().await.unwrap());
+        assert!(poll_fn(|cx| sink.poll_peer_closed(cx)).await);
+    }
+
+    #[tokio::test]
+    async fn poll_drain() {
+        let mut sink = MessageSink::new(Chunked::new(1024, 16));
+        let messages = [random(100), random(200)];
+        sink.write_all(messages.to_vec()).unwrap();
+        poll_fn(|cx| sink.poll_drain(cx)).await.unwrap();
+        assert!(sink.writer.buffer.is_empty());
+        assert_eq!(sink.get_ref().reads, 0);
+        assert_eq!(sink.stats().bytes_read, 0);
+        for message in messages {
+            assert_eq!(Some(message), sink.recv().await.unwrap());
+        }
+
+        let mut sink = MessageSink::new(Broken);
+        sink.write(random(16)).unwrap();
+        for _ in 0..2 {
+            match poll_fn(|cx| sink.poll_drain(cx)).await {
+                Err(SinkError::Write(e)) => assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe),
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+    }
+
+    /// Drive a single half of the sink to completion
+    async fn drive<T>(
+        sink: &mut MessageSink<Endpoint>,
+        mut half: impl FnMut(&mut MessageSink<Endpoint>, &mut Context<'_>) -> Poll<T>,
+    ) -> T {
+        poll_fn(|cx| half(sink, cx)).await
+    }
+
+    #[tokio::test]
+    async fn poll_write_buffer() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let messages = [random(32), random(32)];
+        remote.write(messages[0].clone()).unwrap();
+        remote.flush().await.unwrap();
+        local.write(messages[1].clone()).unwrap();
+        drive(&mut local, MessageSink::poll_write_buffer)
+            .await
+            .unwrap();
+        assert!(local.writer.buffer.is_empty());
+        assert_eq!(local.stats().bytes_read, 0);
+        let message = drive(&mut remote, |sink, cx| {
+            sink.poll_read_frame(cx, FrameReader::poll_next)
+        });
+        assert_eq!(Some(messages[1].clone()), message.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn poll_read_frame() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let messages = [random(32), random(32)];
+        remote.write(messages[0].clone()).unwrap();
+        remote.flush().await.unwrap();
+        local.write(messages[1].clone()).unwrap();
+        let message = drive(&mut local, |sink, cx| {
+            sink.poll_read_frame(cx, FrameReader::poll_next)
+        });
+        assert_eq!(Some(messages[0].clone()), message.await.unwrap());
+        assert_eq!(local.stats().bytes_written, 0);
+        remote.close();
+        assert_eq!(None, remote.recv().await.unwrap());
+        let message = drive(&mut local, |sink, cx| {
+            sink.poll_read_frame(cx, FrameReader::poll_next)
+        });
+        assert_eq!(None, message.await.unwrap());
+        assert!(!local.is_closed());
+        assert!(!local.writer.buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn buffered_while_pending() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let messages = [random(32), random(32)];
+        remote.write_all(messages.to_vec()).unwrap();
+        remote.flush().await.unwrap();
+        assert_eq!(Some(messages[0].clone()), local.recv().await.unwrap());
+        let read = local.stats().bytes_read;
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        match Pin::new(&mut local).poll(&mut cx) {
+            Poll::Ready(Ok(Some(message))) => assert_eq!(messages[1], message),
+            _ => panic!("buffered frame was not returned"),
+        }
+        assert_eq!(local.stats().bytes_read, read);
+        assert!(Pin::new(&mut local).poll(&mut cx).is_pending());
+    }
+
+    #[tokio::test]
+    async fn close_write() {
+        let (local, remote) = Endpoint::pair(1024, 1024);
+        let mut local = MessageSink::new(local);
+        let mut remote = MessageSink::new(remote);
+        let messages = [random(32), random(32)];
+        remote.write(messages[0].clone()).unwrap();
+        remote.flush().await.unwrap();
+        local.write(messages[1].clone()).unwrap();
+        local.close_write();
+        match local.write(random(32)) {
+            Err(SinkError::Closed) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(Some(messages[0].clone()), local.recv().await.unwrap());
+        assert_eq!(Some(messages[1].clone()), remote.recv().await.unwrap());
+        assert_eq!(None, remote.recv().await.unwrap());
+        assert!(!local.is_closed());
+        assert!(local.try_recv().unwrap().is_none());
+        assert_eq!(None, local.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn max_pending_frames() {
+        let messages: Vec<Vec<u8>> = (0..10).map(|_| random(8)).collect();
+        let mut sink = MessageSink::new(Chunked::new(1024, usize::MAX));
+        sink.max_pending_frames(3);
+        sink.write_all(messages.clone()).unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(Some(messages[0].clone()), sink.recv().await.unwrap());
+        assert_eq!(sink.stream.reads, 1);
+        assert_eq!(sink.reader.ready.len(), 2);
+        for message in messages[1..].iter() {
+            assert!(sink.reader.ready.len() < 3);
+            assert_eq!(Some(message.clone()), sink.recv().await.unwrap());
+        }
+        assert_eq!(sink.stream.reads, 1);
+        assert!(sink.reader.buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn into_inner_ready_queue() {
+        let messages = [random(128), random(128)];
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.write_all(messages.to_vec()).unwrap();
+        assert_eq!(Some(messages[0].clone()), sink.recv().await.unwrap());
+        assert_eq!(sink.reader.ready.len(), 1);
+        let (_, mut buffer) = sink.into_inner();
+        let frame = Frame::try_from(&mut buffer, &FrameFormat::default(), usize::MAX).unwrap();
+        assert_eq!(frame.into_message(), messages[1]);
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_messages() {
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.limit(0);
+        sink.write(Vec::new()).unwrap();
+        sink.write_all(vec![Vec::new(), Vec::new()]).unwrap();
+        for _ in 0..3 {
+            assert_eq!(Some(Vec::new()), sink.recv().await.unwrap());
+        }
+        assert_eq!(sink.stats().messages_written, 3);
+        assert_eq!(sink.stats().bytes_read, 12);
+    }
+
+    #[tokio::test]
+    async fn read_timeout() {
+        let mut stream = RingBuffer::new(1024);
+        futures::AsyncWriteExt::write_all(&mut stream, &[128, 0])
+            .await
+            .unwrap();
+        let mut sink = MessageSink::new(stream);
+        sink.read_timeout(Duration::from_millis(20), tokio::time::sleep);
+        match sink.recv().await {
+            Err(SinkError::Timeout) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        assert_eq!(sink.status(), SinkStatus::Errored);
+    }
+
+    #[tokio::test]
+    async fn read_timeout_idle() {
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.read_timeout(Duration::from_millis(20), tokio::time::sleep);
+        let idle = tokio::time::timeout(Duration::from_millis(100), sink.recv()).await;
+        assert!(idle.is_err(), "an idle sink does not time out");
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.keepalive(Duration::from_millis(20), tokio::time::sleep);
+        let idle = tokio::time::timeout(Duration::from_millis(100), sink.recv()).await;
+        assert!(idle.is_err(), "pings are not surfaced as messages");
+        let stats = sink.stats();
+        assert!(stats.bytes_written >= 10, "stats: {:?}", stats);
+        assert_eq!(stats.bytes_written % 5, 0);
+        assert_eq!(stats.bytes_read, stats.bytes_written);
+        assert_eq!(stats.messages_written, 0);
+        assert_eq!(stats.messages_read, 0);
+        let message = random(128);
+        sink.write(message.clone()).unwrap();
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn channels() {
+        let messages = [random(128), random(64), random(32), random(16)];
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.channels(true);
+        for (i, message) in messages.iter().enumerate() {
+            sink.write_to(1 + i as u16 % 2, message.clone()).unwrap();
+        }
+        for (i, message) in messages.into_iter().enumerate() {
+            let received = sink.recv_from().await.unwrap();
+            assert_eq!(Some((1 + i as u16 % 2, message)), received);
+        }
+        sink.write(random(8)).unwrap();
+        assert_eq!(sink.recv_from().await.unwrap().unwrap().0, 0);
+    }
+
+    #[tokio::test]
+    async fn channels_disabled() {
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        match sink.write_to(1, random(8)) {
+            Err(SinkError::Parse(ParseError::Corrupt)) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        sink.write_to(0, random(8)).unwrap();
     }
 }
@@ -0,0 +1,203 @@
+use crate::SinkError;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Callbacks for feeding a [`MessageSink`](crate::MessageSink)'s activity into metrics,
+/// set with [`MessageSink::observer`](crate::MessageSink::observer). Every method does
+/// nothing by default. They are called from inside the sink's polls, so they should be
+/// quick and must not block.
+pub trait Observer: Send {
+    /// A message was handed to the caller
+    fn on_message_read(&mut self) {}
+    /// Every byte of a message's frame was written to the stream
+    fn on_message_written(&mut self) {}
+    /// The sink closed because of `error`
+    fn on_error(&mut self, _error: &SinkError) {}
+    /// Inbound bytes waiting to be received grew to `buffered`, past the
+    /// [`read_buffer_warn_threshold`](crate::MessageSink::read_buffer_warn_threshold).
+    /// Called once each time the threshold is crossed, the sink stays open.
+    fn on_slow_consumer(&mut self, _buffered: usize) {}
+}
+
+/// An optional observer along with what it has been told so far
+#[derive(Default)]
+pub(crate) struct Observed {
+    observer: Option<Box<dyn Observer>>,
+    /// Messages written that the observer has been told about
+    writes: u64,
+    /// Inbound bytes past which the observer is warned of a slow consumer
+    pub(crate) warn_threshold: Option<usize>,
+    /// The observer has been warned since the backlog last fell to the threshold
+    warned: bool,
+}
+
+impl Observed {
+    /// Report to `observer` from now on, `written` messages having gone out already
+    pub(crate) fn set(&mut self, observer: Box<dyn Observer>, written: u64) {
+        self.observer = Some(observer);
+        self.writes = written;
+    }
+    pub(crate) fn read(&mut self) {
+        if let Some(observer) = &mut self.observer {
+            observer.on_message_read();
+        }
+    }
+    /// Tell the observer about every message written since it was last told, out of
+    /// `written` in total
+    pub(crate) fn written(&mut self, written: u64) {
+        let Some(observer) = &mut self.observer else {
+            return;
+        };
+        for _ in self.writes..written {
+            observer.on_message_written();
+        }
+        self.writes = written;
+    }
+    pub(crate) fn error(&mut self, error: &SinkError) {
+        if let Some(observer) = &mut self.observer {
+            observer.on_error(error);
+        }
+    }
+    /// Warn the observer if the inbound backlog has just grown past the warn threshold
+    pub(crate) fn backlog(&mut self, buffered: usize) {
+        let (Some(threshold), Some(observer)) = (self.warn_threshold, &mut self.observer) else {
+            return;
+        };
+        if buffered <= threshold {
+            self.warned = false;
+        } else if !self.warned {
+            self.warned = true;
+            observer.on_slow_consumer(buffered);
+        }
+    }
+    /// One handle for each half of a split sink, both reporting to the same observer
+    pub(crate) fn split(self) -> (Self, Self) {
+        let shared = self.observer.map(|observer| Arc::new(Mutex::new(observer)));
+        let handle = |shared: &Arc<_>| Box::new(Shared(Arc::clone(shared))) as Box<dyn Observer>;
+        let write = Self {
+            observer: shared.as_ref().map(handle),
+            writes: self.writes,
+            warn_threshold: None,
+            warned: false,
+        };
+        let read = Self {
+            observer: shared.as_ref().map(handle),
+            ..self
+        };
+        (read, write)
+    }
+}
+
+/// An observer shared between the halves of a split sink
+struct Shared(Arc<Mutex<Box<dyn Observer>>>);
+
+impl Shared {
+    fn with(&self, f: impl FnOnce(&mut dyn Observer)) {
+        f(&mut **self.0.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl Observer for Shared {
+    fn on_message_read(&mut self) {
+        self.with(|observer| observer.on_message_read())
+    }
+    fn on_message_written(&mut self) {
+        self.with(|observer| observer.on_message_written())
+    }
+    fn on_error(&mut self, error: &SinkError) {
+        self.with(|observer| observer.on_error(error))
+    }
+    fn on_slow_consumer(&mut self, buffered: usize) {
+        self.with(|observer| observer.on_slow_consumer(buffered))
+    }
+}
+
+#[cfg(test)]
+mod observer_test {
+    use super::*;
+    use crate::{FrameFormat, MessageSink};
+    use futures_ringbuf::RingBuffer;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Counts {
+        read: usize,
+        written: usize,
+        errors: usize,
+        slow: Vec<usize>,
+    }
+
+    struct Counter(Arc<Mutex<Counts>>);
+
+    impl Observer for Counter {
+        fn on_message_read(&mut self) {
+            self.0.lock().unwrap().read += 1;
+        }
+        fn on_message_written(&mut self) {
+            self.0.lock().unwrap().written += 1;
+        }
+        fn on_error(&mut self, _error: &SinkError) {
+            self.0.lock().unwrap().errors += 1;
+        }
+        fn on_slow_consumer(&mut self, buffered: usize) {
+            self.0.lock().unwrap().slow.push(buffered);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts() {
+        let counts = Arc::new(Mutex::new(Counts::default()));
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.observer(Counter(counts.clone()));
+        sink.write_all(vec![vec![1; 16], vec![2; 16], vec![3; 16]])
+            .unwrap();
+        sink.flush().await.unwrap();
+        sink.recv().await.unwrap();
+        sink.recv().await.unwrap();
+        let expected = Counts {
+            read: 2,
+            written: 3,
+            errors: 0,
+            slow: Vec::new(),
+        };
+        assert_eq!(*counts.lock().unwrap(), expected);
+
+        // A frame with a bad checksum closes the sink
+        let format = FrameFormat {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut framed = Vec::new();
+        crate::Codec::encode(&format, &[0; 8], &mut framed).unwrap();
+        *framed.last_mut().unwrap() ^= 1;
+        sink.recv().await.unwrap();
+        sink.checksum(true);
+        sink.write_raw(framed).unwrap();
+        assert!(sink.recv().await.is_err());
+        let expected = Counts {
+            read: 3,
+            written: 3,
+            errors: 1,
+            slow: Vec::new(),
+        };
+        assert_eq!(*counts.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn slow_consumer() {
+        let counts = Arc::new(Mutex::new(Counts::default()));
+        let mut sink = MessageSink::new(RingBuffer::new(16 * 1024));
+        sink.observer(Counter(counts.clone()));
+        sink.read_buffer_warn_threshold(Some(4096));
+        sink.read_chunk_size(16 * 1024);
+        for round in 1..=2 {
+            sink.write_all(vec![vec![0; 1000]; 10]).unwrap();
+            sink.flush().await.unwrap();
+            for _ in 0..10 {
+                assert_eq!(Some(vec![0; 1000]), sink.recv().await.unwrap());
+                assert_eq!(sink.status(), crate::SinkStatus::Open);
+            }
+            // Warned once while the backlog was over the threshold
+            assert_eq!(counts.lock().unwrap().slow, vec![9000; round]);
+        }
+    }
+}
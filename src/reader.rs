@@ -0,0 +1,587 @@
+use crate::{
+    codec::Codec,
+    frame::{Chunk, Frame, FrameFormat, FrameKind, ParseError},
+    timer::IdleTimer,
+    SinkError,
+};
+#[cfg(feature = "bytes")]
+use bytes::{Bytes, BytesMut};
+use futures::{
+    io::{AsyncBufRead, AsyncRead},
+    ready,
+};
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "bytes")]
+type ReadBuffer = BytesMut;
+#[cfg(not(feature = "bytes"))]
+type ReadBuffer = Vec<u8>;
+#[cfg(feature = "bytes")]
+type Message = Bytes;
+#[cfg(not(feature = "bytes"))]
+type Message = Vec<u8>;
+/// Least room a datagram read makes, enough for any UDP datagram
+const DATAGRAM: usize = 64 * 1024;
+/// A message along with the channel it arrived on
+pub(crate) type ChannelMessage = (u16, Vec<u8>);
+/// Pulls up to the given number of bytes from the stream into the reader, resolving to how
+/// many were taken, 0 at EOF
+pub(crate) type FillFn<C, R> =
+    fn(&mut FrameReader<C>, &mut R, &mut Context<'_>, usize) -> Poll<io::Result<usize>>;
+
+/// Inbound half of the framing state: bytes read from the stream that have not been parsed
+/// into a message yet.
+pub(crate) struct FrameReader<C = FrameFormat> {
+    pub(crate) buffer: ReadBuffer,
+    /// Messages parsed out of `buffer` that have not been yielded yet, along with the frame
+    /// they arrived in if it was compressed, since compressing them again may not give the
+    /// same frame back
+    pub(crate) ready: VecDeque<(u16, Message, Option<Vec<u8>>)>,
+    /// Most frames parsed onto `ready` at once
+    pub(crate) max_pending: usize,
+    pub(crate) chunk: usize,
+    /// Read only as many bytes as the next frame needs, never past its end
+    pub(crate) exact: bool,
+    pub(crate) format: C,
+    pub(crate) limit: usize,
+    pub(crate) timeout: Option<IdleTimer>,
+    /// Shrink the buffer once its capacity exceeds this multiple of what it needs
+    pub(crate) shrink: Option<usize>,
+    /// Capacity the buffer is never shrunk below
+    pub(crate) capacity: usize,
+    /// Messages longer than this are yielded in chunks by `parse_chunk`
+    pub(crate) chunk_threshold: Option<usize>,
+    /// Body bytes left of the message currently being yielded in chunks
+    pub(crate) streaming: Option<usize>,
+    /// Every read is a message of its own, see `poll_datagram`
+    pub(crate) datagram: bool,
+    pub(crate) bytes_read: u64,
+    pub(crate) messages_read: u64,
+}
+
+impl<C: Default> Default for FrameReader<C> {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<C> FrameReader<C> {
+    pub(crate) fn new(format: C) -> Self {
+        Self {
+            buffer: Default::default(),
+            ready: Default::default(),
+            max_pending: usize::MAX,
+            chunk: 8 * 1024,
+            exact: false,
+            format,
+            limit: usize::MAX,
+            timeout: None,
+            shrink: Some(4),
+            capacity: 0,
+            chunk_threshold: None,
+            streaming: None,
+            datagram: false,
+            bytes_read: 0,
+            messages_read: 0,
+        }
+    }
+}
+
+impl<C: Codec> FrameReader<C> {
+    /// Read from `stream` until a complete message is buffered. Resolves to `Ok(None)` once
+    /// the stream reaches EOF between frames, or `SinkError::UnexpectedEof` if it ends
+    /// partway through one.
+    pub(crate) fn poll_next<R>(
+        &mut self,
+        stream: &mut R,
+        cx: &mut Context<'_>,
+        fill: FillFn<C, R>,
+    ) -> Poll<Result<Option<Vec<u8>>, SinkError>> {
+        self.poll_with(stream, cx, fill, Self::parse)
+    }
+
+    /// Like `poll_next`, but yields messages as slices of the read buffer.
+    #[cfg(feature = "bytes")]
+    pub(crate) fn poll_next_bytes<R>(
+        &mut self,
+        stream: &mut R,
+        cx: &mut Context<'_>,
+        fill: FillFn<C, R>,
+    ) -> Poll<Result<Option<Bytes>, SinkError>> {
+        self.poll_with(stream, cx, fill, Self::parse_bytes)
+    }
+
+    /// Like `poll_next`, but only resolves once a message is ready for `parse_into`.
+    pub(crate) fn poll_next_ready<R>(
+        &mut self,
+        stream: &mut R,
+        cx: &mut Context<'_>,
+        fill: FillFn<C, R>,
+    ) -> Poll<Result<Option<()>, SinkError>> {
+        self.poll_with(stream, cx, fill, Self::parse_ready)
+    }
+
+    /// Like `poll_next`, but yields messages along with the channel they arrived on.
+    pub(crate) fn poll_next_from<R>(
+        &mut self,
+        stream: &mut R,
+        cx: &mut Context<'_>,
+        fill: FillFn<C, R>,
+    ) -> Poll<Result<Option<ChannelMessage>, SinkError>> {
+        self.poll_with(stream, cx, fill, Self::parse_from)
+    }
+
+    fn poll_with<R, T>(
+        &mut self,
+        stream: &mut R,
+        cx: &mut Context<'_>,
+        fill: FillFn<C, R>,
+        parse: fn(&mut Self) -> Result<Option<T>, SinkError>,
+    ) -> Poll<Result<Option<T>, SinkError>> {
+        let read = self.bytes_read;
+        let mut eof = false;
+        // Parse once on entry and once after every read that added bytes, so frames already
+        // buffered are returned before the stream is polled at all
+        loop {
+            if let Some(message) = parse(self)? {
+                return Poll::Ready(Ok(Some(message)));
+            }
+            let chunk = match self.exact {
+                true => self.format.needed(&self.buffer, self.limit),
+                false => self.chunk,
+            };
+            if chunk == 0 {
+                break;
+            }
+            match fill(self, stream, cx, chunk) {
+                Poll::Ready(Ok(0)) => {
+                    eof = true;
+                    break;
+                }
+                Poll::Ready(Ok(filled)) => self.bytes_read += filled as u64,
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Not an error, just a stream that can't wait for readiness itself. It
+                // hasn't necessarily registered the task, so ask to be polled again.
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    cx.waker().wake_by_ref();
+                    break;
+                }
+                Poll::Ready(Err(e)) => {
+                    return Poll::Ready(Err(SinkError::read(e)));
+                }
+                Poll::Pending => {
+                    break;
+                }
+            };
+        }
+        let partial = !self.buffer.is_empty() || self.streaming.is_some();
+        if eof && partial {
+            return Poll::Ready(Err(SinkError::UnexpectedEof));
+        }
+        if eof {
+            return Poll::Ready(Ok(None));
+        }
+        if let Some(timeout) = &mut self.timeout {
+            if timeout.expired(cx, partial, self.bytes_read != read) {
+                return Poll::Ready(Err(SinkError::Timeout));
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Read up to `chunk` bytes from `stream` onto the end of the buffer.
+    pub(crate) fn poll_read<R>(
+        &mut self,
+        stream: &mut R,
+        cx: &mut Context<'_>,
+        chunk: usize,
+    ) -> Poll<io::Result<usize>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        // Read straight into the tail of the buffer and trim whatever wasn't filled
+        let start = self.buffer.len();
+        self.buffer.resize(start + chunk, 0);
+        let result = Pin::new(stream).poll_read(cx, &mut self.buffer[start..]);
+        let filled = match result {
+            Poll::Ready(Ok(length)) => length,
+            _ => 0,
+        };
+        self.buffer.truncate(start + filled);
+        result
+    }
+
+    /// Like `poll_read`, for a stream that returns one datagram per read. Each datagram is
+    /// queued as a message as it is, without any framing. One over `limit` is framed into
+    /// the buffer instead, for parsing to reject it like any other frame. One that fills the
+    /// whole read may have been cut short by the stream, and fails with `InvalidData`.
+    pub(crate) fn poll_datagram<R>(
+        &mut self,
+        stream: &mut R,
+        cx: &mut Context<'_>,
+        _chunk: usize,
+    ) -> Poll<io::Result<usize>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let start = self.buffer.len();
+        // Room for anything up to the limit, and for a whole UDP datagram either way so an
+        // oversized one is reported at its real size
+        let size = match self.limit {
+            usize::MAX => DATAGRAM,
+            limit => limit.max(DATAGRAM),
+        } + 1;
+        let length = ready!(self.poll_read(stream, cx, size))?;
+        if length == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        if length == size {
+            self.buffer.truncate(start);
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "datagram may have been truncated",
+            )));
+        }
+        let datagram = self.buffer.split_off(start);
+        // A datagram the header can't describe couldn't be handed back by `take_buffer`
+        if length <= self.limit && self.format.header(&datagram).is_ok() {
+            self.ready.push_back((0, Message::from(datagram), None));
+            return Poll::Ready(Ok(length));
+        }
+        let header = self
+            .format
+            .header(&datagram)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.buffer.extend_from_slice(&header);
+        self.buffer.extend_from_slice(&datagram);
+        Poll::Ready(Ok(length))
+    }
+
+    /// Like `poll_read`, but takes bytes out of a stream that buffers them itself. Complete
+    /// frames at the front of the stream's buffer are parsed where they are, only a
+    /// partial frame is copied into our own buffer to wait for the rest.
+    pub(crate) fn poll_fill_buf<R>(
+        &mut self,
+        stream: &mut R,
+        cx: &mut Context<'_>,
+        chunk: usize,
+    ) -> Poll<io::Result<usize>>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let available = ready!(Pin::new(&mut *stream).poll_fill_buf(cx))?;
+        let mut used = 0;
+        // Taking frames out of the stream's buffer consumes them, so an exact reader stops
+        // at one
+        let max_pending = match self.exact {
+            true => 1,
+            false => self.max_pending,
+        };
+        if self.buffer.is_empty() {
+            while self.ready.len() < max_pending {
+                // Anything but a complete frame is left for the buffered path to handle
+                let frame = &available[used..];
+                let Ok(span) = self.format.decode(frame, self.limit) else {
+                    break;
+                };
+                if span.kind == FrameKind::Message {
+                    let Ok(message) = span.extract(frame, self.limit) else {
+                        break;
+                    };
+                    let compressed = span.compressed.then(|| frame[..span.total].to_vec());
+                    self.ready
+                        .push_back((span.channel, Message::from(message), compressed));
+                }
+                used += span.total;
+            }
+        }
+        if used == 0 {
+            // An exact reader asks for no more than the frame needs
+            used = available.len().min(chunk);
+            self.buffer.extend_from_slice(&available[..used]);
+        }
+        Pin::new(stream).consume(used);
+        Poll::Ready(Ok(used))
+    }
+
+    /// Pop the next parsed message, parsing every complete frame already buffered if none
+    /// are queued. Never touches the stream.
+    pub(crate) fn parse(&mut self) -> Result<Option<Vec<u8>>, SinkError> {
+        Ok(self.pop()?.map(|(_, message)| message))
+    }
+
+    /// Like `parse`, but yields the message as a slice of the read buffer instead of a copy.
+    #[cfg(feature = "bytes")]
+    pub(crate) fn parse_bytes(&mut self) -> Result<Option<Bytes>, SinkError> {
+        Ok(self.pop()?.map(|(_, message)| message))
+    }
+
+    /// Like `parse`, but yields the message along with the channel it arrived on.
+    pub(crate) fn parse_from(&mut self) -> Result<Option<ChannelMessage>, SinkError> {
+        self.pop()
+    }
+
+    /// Find out whether a message is ready to be taken, without copying it out of the read
+    /// buffer. Control frames in front of it are dropped.
+    pub(crate) fn parse_ready(&mut self) -> Result<Option<()>, SinkError> {
+        while self.ready.is_empty() {
+            match self.format.decode(&self.buffer, self.limit) {
+                Ok(span) if span.kind != FrameKind::Message => self.advance(span.total),
+                Ok(span) if !span.compressed => break,
+                Err(ParseError::NotReady) => return Ok(None),
+                // Compressed messages and errors take the usual route
+                _ => {
+                    self.fill()?;
+                    return Ok((!self.ready.is_empty()).then_some(()));
+                }
+            }
+        }
+        Ok(Some(()))
+    }
+
+    /// Like `parse`, but copies the message into `out` in place of its contents. A message
+    /// still in the read buffer is copied straight out of it, never into one of its own.
+    pub(crate) fn parse_into(&mut self, out: &mut Vec<u8>) -> Result<Option<usize>, SinkError> {
+        if self.parse_ready()?.is_none() {
+            return Ok(None);
+        }
+        out.clear();
+        match self.ready.pop_front() {
+            Some((_, message, _)) => out.extend_from_slice(&message),
+            None => {
+                let span = self
+                    .format
+                    .decode(&self.buffer, self.limit)
+                    .expect("parse_ready found a complete frame");
+                out.extend_from_slice(&self.buffer[span.body.clone()]);
+                self.advance(span.total);
+            }
+        }
+        self.messages_read += 1;
+        Ok(Some(out.len()))
+    }
+
+    /// Take ownership of the bytes that have been read but not consumed yet, including
+    /// frames that were parsed but not yielded.
+    pub(crate) fn into_buffer(mut self) -> Vec<u8> {
+        self.take_buffer()
+    }
+
+    /// Like `into_buffer`, but leaves the reader empty rather than consuming it.
+    pub(crate) fn take_buffer(&mut self) -> Vec<u8> {
+        // Hand back the buffer itself when nothing was parsed ahead, so it can be reused
+        #[cfg(not(feature = "bytes"))]
+        if self.ready.is_empty() {
+            return std::mem::take(&mut self.buffer);
+        }
+        let mut buffer = Vec::new();
+        for (channel, message, compressed) in self.ready.drain(..) {
+            if let Some(frame) = compressed {
+                buffer.extend(frame);
+                continue;
+            }
+            // Any other message framed the same way it arrived, so this only fails for a
+            // codec that can't frame what it decoded
+            if let Ok(header) = self.format.channel_header(channel, &message) {
+                buffer.extend(header);
+            }
+            buffer.extend(&message[..]);
+            buffer.extend(self.format.trailer(&message));
+        }
+        buffer.extend(&self.buffer[..]);
+        self.buffer.clear();
+        buffer
+    }
+
+    /// Bytes read but not yet yielded, parsed or not.
+    pub(crate) fn buffered(&self) -> usize {
+        let parsed: usize = self.ready.iter().map(|(_, message, _)| message.len()).sum();
+        parsed + self.buffer.len()
+    }
+
+    /// Drop everything read but not yet yielded, e.g. when the stream is replaced.
+    pub(crate) fn reset(&mut self) {
+        self.ready.clear();
+        self.buffer.clear();
+        self.streaming = None;
+    }
+
+    /// Read into `buffer`'s allocation from now on, discarding its contents.
+    pub(crate) fn recycle(&mut self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        #[cfg(feature = "bytes")]
+        {
+            self.buffer = BytesMut::with_capacity(buffer.capacity());
+        }
+        #[cfg(not(feature = "bytes"))]
+        {
+            self.buffer = buffer;
+        }
+    }
+
+    /// Release capacity beyond the buffered bytes plus one more read.
+    pub(crate) fn compact(&mut self) {
+        let needed = (self.buffer.len() + self.chunk).max(self.capacity);
+        if self.buffer.capacity() <= needed {
+            return;
+        }
+        #[cfg(feature = "bytes")]
+        {
+            let mut buffer = BytesMut::with_capacity(needed);
+            buffer.extend_from_slice(&self.buffer);
+            self.buffer = buffer;
+        }
+        #[cfg(not(feature = "bytes"))]
+        self.buffer.shrink_to(needed);
+    }
+
+    /// Drop the first `length` bytes of the buffer
+    fn advance(&mut self, length: usize) {
+        #[cfg(feature = "bytes")]
+        drop(self.buffer.split_to(length));
+        #[cfg(not(feature = "bytes"))]
+        self.buffer.drain(..length);
+    }
+
+    fn pop<T: From<Message>>(&mut self) -> Result<Option<(u16, T)>, SinkError> {
+        self.fill()?;
+        let message = self.ready.pop_front();
+        if message.is_some() {
+            self.messages_read += 1;
+        }
+        Ok(message.map(|(channel, message, _)| (channel, T::from(message))))
+    }
+
+    /// Parse complete frames in the buffer onto the ready queue, if it is empty, up to
+    /// `max_pending` of them. An error behind one or more complete frames is left in the
+    /// buffer to be reported once they have been consumed.
+    pub(crate) fn fill(&mut self) -> Result<(), SinkError> {
+        if !self.ready.is_empty() {
+            return Ok(());
+        }
+        let mut offset = 0;
+        let mut bodies = Vec::new();
+        while bodies.len() < self.max_pending {
+            let frame = &self.buffer[offset..];
+            match self.format.decode(frame, self.limit) {
+                // Control frames are consumed without being surfaced
+                Ok(span) if span.kind != FrameKind::Message => offset += span.total,
+                // Compressed messages are inflated into a buffer of their own
+                Ok(span) if span.compressed => match span.extract(frame, self.limit) {
+                    Ok(message) => {
+                        let compressed = frame[..span.total].to_vec();
+                        bodies.push((span.channel, 0..0, Some((message, compressed))));
+                        offset += span.total;
+                    }
+                    Err(_) if !bodies.is_empty() => break,
+                    Err(ParseError::LimitExceeded) => return Err(SinkError::LimitExceeded),
+                    Err(e) => return Err(e.into()),
+                },
+                Ok(span) => {
+                    let body = offset + span.body.start..offset + span.body.end;
+                    bodies.push((span.channel, body, None));
+                    offset += span.total;
+                }
+                Err(ParseError::NotReady) => break,
+                Err(_) if !bodies.is_empty() => break,
+                Err(ParseError::LimitExceeded) => return Err(SinkError::LimitExceeded),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        #[cfg(feature = "bytes")]
+        {
+            let frames = self.buffer.split_to(offset).freeze();
+            self.ready.extend(
+                bodies
+                    .into_iter()
+                    .map(|(channel, body, inflated)| match inflated {
+                        Some((message, frame)) => (channel, Bytes::from(message), Some(frame)),
+                        None => (channel, frames.slice(body), None),
+                    }),
+            );
+        }
+        #[cfg(not(feature = "bytes"))]
+        {
+            let buffer = &self.buffer;
+            self.ready.extend(
+                bodies
+                    .into_iter()
+                    .map(|(channel, body, inflated)| match inflated {
+                        Some((message, frame)) => (channel, message, Some(frame)),
+                        None => (channel, buffer[body].to_vec(), None),
+                    }),
+            );
+            self.buffer.drain(0..offset);
+        }
+        if let Some(multiple) = self.shrink {
+            let needed = self.buffer.len() + self.chunk;
+            if offset > 0 && self.buffer.capacity() > needed.saturating_mul(multiple) {
+                self.compact();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FrameReader<FrameFormat> {
+    /// Like `poll_next`, but yields messages in chunks, see `parse_chunk`.
+    pub(crate) fn poll_next_chunk<R>(
+        &mut self,
+        stream: &mut R,
+        cx: &mut Context<'_>,
+        fill: FillFn<FrameFormat, R>,
+    ) -> Poll<Result<Option<Chunk>, SinkError>> {
+        self.poll_with(stream, cx, fill, Self::parse_chunk)
+    }
+
+    /// Like `parse`, but a message longer than `chunk_threshold` is yielded a chunk at a
+    /// time as its body arrives, rather than once it is buffered whole. Frames with a
+    /// checksum or a compressed body are always buffered whole, since both need the entire
+    /// body.
+    pub(crate) fn parse_chunk(&mut self) -> Result<Option<Chunk>, SinkError> {
+        if let Some(remaining) = self.streaming {
+            if self.buffer.is_empty() {
+                return Ok(None);
+            }
+            let length = remaining.min(self.buffer.len());
+            let data = self.buffer[..length].to_vec();
+            self.advance(length);
+            let is_last = length == remaining;
+            self.streaming = match is_last {
+                true => None,
+                false => Some(remaining - length),
+            };
+            if is_last {
+                self.messages_read += 1;
+            }
+            return Ok(Some(Chunk { data, is_last }));
+        }
+        if let Some(message) = self.parse()? {
+            return Ok(Some(Chunk {
+                data: message,
+                is_last: true,
+            }));
+        }
+        let Some(threshold) = self.chunk_threshold else {
+            return Ok(None);
+        };
+        match Frame::locate_header(&self.buffer, &self.format, self.limit) {
+            Ok(span)
+                if span.kind == FrameKind::Message
+                    && !self.format.checksum
+                    && !span.compressed
+                    && span.body.len() > threshold =>
+            {
+                self.advance(span.body.start);
+                self.streaming = Some(span.body.len());
+                self.parse_chunk()
+            }
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use crate::{
+    codec::Codec,
+    frame::{FrameFormat, FrameKind, ParseError},
+};
+
+/// Splits bytes read by some other means into messages, for callers running their own I/O
+/// loop. Push bytes in whatever pieces they arrive, then call `next` until it returns
+/// `Ok(None)`.
+#[derive(Debug, Default)]
+pub struct FrameDecoder<C = FrameFormat> {
+    codec: C,
+    buffer: Vec<u8>,
+    /// Bytes at the start of `buffer` that have already been decoded
+    consumed: usize,
+    limit: usize,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::with_codec(FrameFormat::default())
+    }
+}
+
+impl<C: Codec> FrameDecoder<C> {
+    pub fn with_codec(codec: C) -> Self {
+        Self {
+            codec,
+            buffer: Vec::new(),
+            consumed: 0,
+            limit: usize::MAX,
+        }
+    }
+    /// Maximum size of a single message, see [`MessageSink::limit`](crate::MessageSink::limit)
+    pub fn limit(&mut self, bytes: usize) {
+        self.limit = bytes;
+    }
+    /// Buffer `bytes` to be decoded
+    pub fn push(&mut self, bytes: &[u8]) {
+        // Only move what is left once there is more to append behind it
+        self.buffer.drain(..self.consumed);
+        self.consumed = 0;
+        self.buffer.extend_from_slice(bytes);
+    }
+    /// The next complete message, or `Ok(None)` until more bytes are pushed. Control frames
+    /// are dropped. After an error the rest of the buffered bytes can't be trusted.
+    // Not `Iterator::next`: `Ok(None)` means "push more", not that decoding is over
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        loop {
+            let frame = &self.buffer[self.consumed..];
+            let span = match self.codec.decode(frame, self.limit) {
+                Ok(span) => span,
+                Err(ParseError::NotReady) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let message = match span.kind {
+                FrameKind::Message => Some(span.extract(frame, self.limit)?),
+                _ => None,
+            };
+            self.consumed += span.total;
+            if message.is_some() {
+                return Ok(message);
+            }
+        }
+    }
+    /// Number of bytes pushed that have not been decoded yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.consumed
+    }
+}
+
+/// Frames messages for callers running their own I/O loop
+#[derive(Debug, Default)]
+pub struct FrameEncoder<C = FrameFormat> {
+    codec: C,
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        Self::with_codec(FrameFormat::default())
+    }
+}
+
+impl<C: Codec> FrameEncoder<C> {
+    pub fn with_codec(codec: C) -> Self {
+        Self { codec }
+    }
+    /// `message` framed, ready to be written. Fails if the codec can't frame it, e.g. when
+    /// it is too long for the header width.
+    pub fn encode(&self, message: &[u8]) -> Result<Vec<u8>, ParseError> {
+        let (header, body, trailer) = self.codec.frame(0, message.to_vec())?;
+        Ok([header, body, trailer].concat())
+    }
+}
+
+#[cfg(test)]
+mod sans_io_test {
+    use super::*;
+    use crate::{DelimiterCodec, Endianness, HeaderWidth, LengthSemantics, Magic};
+    use proptest::{collection::vec, prelude::*, sample::Index};
+    use rand::{Rng, RngCore};
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    /// Push `stream` into `decoder` in the pieces given by `splits`, collecting every
+    /// message decoded along the way
+    fn decode_split<C: Codec>(
+        decoder: &mut FrameDecoder<C>,
+        stream: &[u8],
+        splits: &[usize],
+    ) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        let mut start = 0;
+        for &end in splits.iter().chain([stream.len()].iter()) {
+            decoder.push(&stream[start..end]);
+            start = end;
+            while let Some(message) = decoder.next().unwrap() {
+                messages.push(message);
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let encoder = FrameEncoder::new();
+        let messages = vec![random(300), Vec::new(), random(5)];
+        let stream: Vec<u8> = messages
+            .iter()
+            .flat_map(|message| encoder.encode(message).unwrap())
+            .collect();
+        let mut decoder = FrameDecoder::new();
+        let splits: Vec<usize> = (1..stream.len()).collect();
+        assert_eq!(decode_split(&mut decoder, &stream, &splits), messages);
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn split_inside_header() {
+        let encoder = FrameEncoder::new();
+        let message = random(64);
+        let stream = [
+            encoder.encode(&message).unwrap(),
+            encoder.encode(&[]).unwrap(),
+        ]
+        .concat();
+        for split in 1..4 {
+            let mut decoder = FrameDecoder::new();
+            decoder.push(&stream[..split]);
+            assert_eq!(decoder.next(), Ok(None));
+            assert_eq!(decoder.buffered(), split);
+            decoder.push(&stream[split..]);
+            assert_eq!(decoder.next(), Ok(Some(message.clone())));
+            assert_eq!(decoder.next(), Ok(Some(Vec::new())));
+            assert_eq!(decoder.next(), Ok(None));
+        }
+    }
+
+    #[test]
+    fn arbitrary_splits() {
+        let mut rng = rand::thread_rng();
+        let format = FrameFormat {
+            width: HeaderWidth::U16,
+            tagged: true,
+            ..Default::default()
+        };
+        let encoder = FrameEncoder::with_codec(format.clone());
+        for _ in 0..100 {
+            let messages: Vec<_> = (0..rng.gen_range(1..20))
+                .map(|_| random(rng.gen_range(0..512)))
+                .collect();
+            let mut stream = Vec::new();
+            for message in &messages {
+                stream.extend(encoder.encode(message).unwrap());
+                // Control frames between messages are dropped
+                stream.extend(format.ping().unwrap());
+            }
+            let mut splits: Vec<usize> = (0..rng.gen_range(0..32))
+                .map(|_| rng.gen_range(0..=stream.len()))
+                .collect();
+            splits.sort_unstable();
+            let mut decoder = FrameDecoder::with_codec(format.clone());
+            assert_eq!(decode_split(&mut decoder, &stream, &splits), messages);
+            assert_eq!(decoder.buffered(), 0);
+        }
+    }
+
+    #[test]
+    fn codec() {
+        let encoder = FrameEncoder::with_codec(DelimiterCodec::new(b'\n'));
+        let stream = [
+            encoder.encode(b"one").unwrap(),
+            encoder.encode(b"two").unwrap(),
+        ]
+        .concat();
+        assert_eq!(stream, b"one\ntwo\n");
+        let mut decoder = FrameDecoder::with_codec(DelimiterCodec::new(b'\n'));
+        let messages = decode_split(&mut decoder, &stream, &[2, 5]);
+        assert_eq!(messages, [b"one".to_vec(), b"two".to_vec()]);
+    }
+
+    #[test]
+    fn errors() {
+        let encoder = FrameEncoder::with_codec(FrameFormat {
+            width: HeaderWidth::U8,
+            ..Default::default()
+        });
+        assert!(encoder.encode(&[0; 256]).is_err());
+        let mut decoder = FrameDecoder::new();
+        decoder.limit(16);
+        decoder.push(&FrameEncoder::new().encode(&[0; 17]).unwrap()[..4]);
+        assert_eq!(
+            decoder.next(),
+            Err(ParseError::TooLarge {
+                declared: 17,
+                limit: 16
+            })
+        );
+    }
+
+    fn formats() -> impl Strategy<Value = FrameFormat> {
+        let widths = prop_oneof![
+            Just(HeaderWidth::U8),
+            Just(HeaderWidth::U16),
+            Just(HeaderWidth::U32),
+            Just(HeaderWidth::U64),
+            Just(HeaderWidth::Varint),
+            Just(HeaderWidth::Packed),
+        ];
+        (widths, any::<[bool; 5]>()).prop_map(
+            |(width, [big, inclusive, checksum, tagged, magic])| FrameFormat {
+                magic: magic.then(|| Magic {
+                    prefix: b"MS".to_vec(),
+                    version: 1,
+                }),
+                width,
+                endianness: match big {
+                    true => Endianness::Big,
+                    false => Endianness::Little,
+                },
+                length: match inclusive {
+                    true => LengthSemantics::Inclusive,
+                    false => LengthSemantics::PayloadOnly,
+                },
+                checksum,
+                tagged,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Short messages, and a few long enough to overflow narrow headers
+    fn messages() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        let length = prop_oneof![4 => 0..300usize, 1 => 60_000..70_000usize];
+        let message = (length, any::<u8>())
+            .prop_map(|(length, seed)| (0..length).map(|i| seed.wrapping_add(i as u8)).collect());
+        vec(message, 0..8)
+    }
+
+    proptest! {
+        /// Untrusted bytes decode to messages within the limit or to an error, never a panic
+        #[test]
+        fn untrusted_bytes(
+            format in formats(),
+            bytes in vec(any::<u8>(), 0..1024),
+            split in any::<Index>(),
+            limit in 0..4096usize,
+        ) {
+            let mut decoder = FrameDecoder::with_codec(format);
+            decoder.limit(limit);
+            let split = split.index(bytes.len() + 1);
+            for piece in [&bytes[..split], &bytes[split..]] {
+                decoder.push(piece);
+                loop {
+                    match decoder.next() {
+                        Ok(Some(message)) => prop_assert!(message.len() <= limit),
+                        Ok(None) => break,
+                        Err(e) => {
+                            prop_assert_ne!(e, ParseError::NotReady);
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Whatever encodes decodes back the same, however the stream is split
+        #[test]
+        fn round_trip(
+            format in formats(),
+            messages in messages(),
+            splits in vec(any::<Index>(), 0..8),
+        ) {
+            let encoder = FrameEncoder::with_codec(format.clone());
+            let mut stream = Vec::new();
+            let mut expected = Vec::new();
+            for message in messages {
+                match encoder.encode(&message) {
+                    Ok(frame) => {
+                        stream.extend(frame);
+                        expected.push(message);
+                    }
+                    // Only a narrow header can run out of room
+                    Err(_) => {
+                        let narrow = matches!(format.width, HeaderWidth::U8 | HeaderWidth::U16);
+                        prop_assert!(narrow);
+                    }
+                }
+            }
+            let mut splits: Vec<usize> = splits
+                .iter()
+                .map(|split| split.index(stream.len() + 1))
+                .collect();
+            splits.sort_unstable();
+            let mut decoder = FrameDecoder::with_codec(format);
+            prop_assert_eq!(decode_split(&mut decoder, &stream, &splits), expected);
+            prop_assert_eq!(decoder.buffered(), 0);
+        }
+    }
+}
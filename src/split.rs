@@ -0,0 +1,289 @@
+use crate::{
+    codec::Codec, observer::Observed, reader::FrameReader, writer::FrameWriter, FrameFormat,
+    SinkError,
+};
+use futures::{
+    future::poll_fn,
+    io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    ready, Sink, Stream,
+};
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The read half of a [`MessageSink`](crate::MessageSink), yielding inbound messages.
+/// A sink built with `from_buf_read` is read through `poll_read` once split, which still
+/// hands over whatever the `AsyncBufRead` has buffered first.
+pub struct SinkReader<S, C = FrameFormat>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream: ReadHalf<S>,
+    reader: FrameReader<C>,
+    observed: Observed,
+    done: bool,
+}
+
+/// The write half of a [`MessageSink`](crate::MessageSink), framing outbound messages.
+pub struct SinkWriter<S, C = FrameFormat>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream: WriteHalf<S>,
+    writer: FrameWriter<C>,
+    observed: Observed,
+    closed: bool,
+}
+
+impl<S, C> SinkReader<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    pub(crate) fn new(stream: ReadHalf<S>, reader: FrameReader<C>, observed: Observed) -> Self {
+        Self {
+            stream,
+            reader,
+            observed,
+            done: false,
+        }
+    }
+}
+
+impl<S, C> SinkWriter<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    pub(crate) fn new(stream: WriteHalf<S>, writer: FrameWriter<C>, observed: Observed) -> Self {
+        Self {
+            stream,
+            writer,
+            observed,
+            closed: false,
+        }
+    }
+    pub fn write(&mut self, message: Vec<u8>) -> Result<usize, SinkError> {
+        self.writer.write(message)
+    }
+    /// Send keepalive pings while there is nothing else to write, for a sink with
+    /// `keepalive` configured. Only resolves if writing fails or the half is closed, so
+    /// race it against whatever produces the next message.
+    pub async fn idle(&mut self) -> Result<Infallible, SinkError> {
+        poll_fn(|cx| {
+            if self.closed {
+                return Poll::Ready(Err(SinkError::Closed));
+            }
+            self.writer.poll_keepalive(cx);
+            ready!(self.poll_write(cx))?;
+            Poll::Pending
+        })
+        .await
+    }
+    /// Write the outbound buffer to the stream until it is empty, telling the observer
+    /// about what went out
+    fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SinkError>> {
+        let result = self.writer.poll_flush(&mut self.stream, cx);
+        self.observe(result)
+    }
+    fn observe(&mut self, result: Poll<Result<(), SinkError>>) -> Poll<Result<(), SinkError>> {
+        self.observed.written(self.writer.messages_written);
+        if let Poll::Ready(Err(e)) = &result {
+            self.observed.error(e);
+        }
+        result
+    }
+}
+
+impl<S, C> Stream for SinkReader<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    type Item = Result<Vec<u8>, SinkError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let half = self.get_mut();
+        if half.done {
+            return Poll::Ready(None);
+        }
+        let fill = match half.reader.datagram {
+            true => FrameReader::poll_datagram,
+            false => FrameReader::poll_read,
+        };
+        let result = half.reader.poll_next(&mut half.stream, cx, fill);
+        half.observed.backlog(half.reader.buffered());
+        match ready!(result) {
+            Ok(Some(message)) => {
+                half.observed.read();
+                Poll::Ready(Some(Ok(message)))
+            }
+            Ok(None) => {
+                half.done = true;
+                Poll::Ready(None)
+            }
+            Err(e) => {
+                half.done = true;
+                half.observed.error(&e);
+                Poll::Ready(Some(Err(e)))
+            }
+        }
+    }
+}
+
+impl<S, C> Sink<Vec<u8>> for SinkWriter<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    type Error = SinkError;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let half = self.get_mut();
+        if half.closed {
+            return Poll::Ready(Err(SinkError::Closed));
+        }
+        half.writer.poll_keepalive(cx);
+        let result = half.writer.poll_ready(&mut half.stream, cx);
+        half.observe(result)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.get_mut().write(item).map(drop)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let half = self.get_mut();
+        if half.closed {
+            return Poll::Ready(Err(SinkError::Closed));
+        }
+        half.writer.poll_keepalive(cx);
+        ready!(half.poll_write(cx))?;
+        Pin::new(&mut half.stream)
+            .poll_flush(cx)
+            .map_err(SinkError::Write)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let half = self.get_mut();
+        if half.closed {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(half.poll_write(cx))?;
+        ready!(Pin::new(&mut half.stream).poll_close(cx)).map_err(SinkError::Write)?;
+        half.closed = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod split_test {
+    use crate::{MessageSink, Observer};
+    use futures::{future, io::BufReader, SinkExt, StreamExt};
+    use futures_ringbuf::RingBuffer;
+    use rand::RngCore;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn split() {
+        let messages = vec![random(128), random(2048), random(128)];
+        let stream = RingBuffer::new(1024);
+        let (reader, mut writer) = MessageSink::new(stream).split();
+        let sent = messages.clone();
+        let writes = tokio::spawn(async move {
+            for message in sent {
+                writer.send(message).await.unwrap();
+            }
+        });
+        let received: Vec<Vec<u8>> = reader.take(3).map(Result::unwrap).collect().await;
+        writes.await.unwrap();
+        assert_eq!(messages, received);
+    }
+
+    #[tokio::test]
+    async fn split_carries_buffers() {
+        let message = random(128);
+        let stream = RingBuffer::new(1024);
+        let mut sink = MessageSink::new(stream);
+        sink.write(message.clone()).unwrap();
+        let (mut reader, mut writer) = sink.split();
+        writer.flush().await.unwrap();
+        assert_eq!(message, reader.next().await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn split_close() {
+        let stream = RingBuffer::new(1024);
+        let (mut reader, mut writer) = MessageSink::new(stream).split();
+        writer.close().await.unwrap();
+        assert!(reader.next().await.is_none());
+        assert!(writer.send(random(8)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn split_keepalive() {
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.keepalive(Duration::from_millis(20), tokio::time::sleep);
+        let (mut reader, mut writer) = sink.split();
+        let idle = future::select(reader.next(), Box::pin(writer.idle()));
+        let timeout = tokio::time::timeout(Duration::from_millis(100), idle);
+        assert!(timeout.await.is_err(), "pings are not surfaced as messages");
+        assert!(
+            reader.reader.bytes_read >= 10,
+            "read {}",
+            reader.reader.bytes_read
+        );
+        assert_eq!(reader.reader.bytes_read % 5, 0);
+        assert_eq!(reader.reader.bytes_read, writer.writer.bytes_written);
+        let message = random(128);
+        writer.send(message.clone()).await.unwrap();
+        assert_eq!(message, reader.next().await.unwrap().unwrap());
+        writer.close().await.unwrap();
+        assert!(matches!(writer.idle().await, Err(crate::SinkError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn split_observer() {
+        #[derive(Clone, Default)]
+        struct Counter(Arc<Mutex<(usize, usize)>>);
+        impl Observer for Counter {
+            fn on_message_read(&mut self) {
+                self.0.lock().unwrap().0 += 1;
+            }
+            fn on_message_written(&mut self) {
+                self.0.lock().unwrap().1 += 1;
+            }
+        }
+        let counter = Counter::default();
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.observer(counter.clone());
+        sink.write(random(16)).unwrap();
+        let (mut reader, mut writer) = sink.split();
+        writer.send(random(16)).await.unwrap();
+        reader.next().await.unwrap().unwrap();
+        assert_eq!(*counter.0.lock().unwrap(), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn split_buf_read() {
+        let messages = [vec![1; 100], vec![2; 200], vec![3; 300]];
+        // Bytes left in the stream's buffer, and frames straddling it
+        for capacity in [1024, 64] {
+            let mut sink = MessageSink::new(RingBuffer::new(1024));
+            sink.write_all(messages.to_vec()).unwrap();
+            sink.flush().await.unwrap();
+            let stream = BufReader::with_capacity(capacity, sink.into_inner().0);
+            let mut sink = MessageSink::from_buf_read(stream);
+            assert_eq!(Some(messages[0].clone()), sink.recv().await.unwrap());
+            let (reader, _writer) = sink.split();
+            let received: Vec<_> = reader.take(2).map(Result::unwrap).collect().await;
+            assert_eq!(&messages[1..], &received[..]);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{Codec, FrameFormat, MessageSink, SinkError};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    Stream,
+};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A [`MessageSink`] that yields at most `n` messages. It reads only as many bytes as each
+/// frame needs, so anything the peer sent after the last message is left unread.
+pub struct TakeSink<S, C = FrameFormat>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    sink: MessageSink<S, C>,
+    remaining: usize,
+}
+
+impl<S, C> TakeSink<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    pub(crate) fn new(mut sink: MessageSink<S, C>, n: usize) -> Self {
+        sink.reader.exact = true;
+        Self { sink, remaining: n }
+    }
+    /// Number of messages left to yield
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+    /// See [`MessageSink::into_inner`]
+    pub fn into_inner(self) -> (S, Vec<u8>) {
+        self.sink.into_inner()
+    }
+}
+
+impl<S, C> Stream for TakeSink<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    type Item = Result<Vec<u8>, SinkError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let take = self.get_mut();
+        if take.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        let item = Pin::new(&mut take.sink).poll_next(cx);
+        if let Poll::Ready(Some(Ok(_))) = item {
+            take.remaining -= 1;
+        }
+        item
+    }
+}
+
+#[cfg(test)]
+mod take_test {
+    use super::*;
+    use futures::{AsyncWriteExt, StreamExt};
+    use futures_ringbuf::RingBuffer;
+
+    #[tokio::test]
+    async fn take() {
+        let messages = [vec![1; 100], vec![2; 200], vec![3; 300]];
+        let mut stream = RingBuffer::new(1024);
+        for message in messages.iter() {
+            let mut frame = (message.len() as u32).to_le_bytes().to_vec();
+            frame.extend(message);
+            stream.write_all(&frame).await.unwrap();
+        }
+        let mut take = MessageSink::new(stream).take(2);
+        assert_eq!(messages[0], take.next().await.unwrap().unwrap());
+        assert_eq!(messages[1], take.next().await.unwrap().unwrap());
+        assert!(take.next().await.is_none());
+        assert_eq!(take.remaining(), 0);
+        let (mut stream, buffer) = take.into_inner();
+        assert!(buffer.is_empty());
+        let mut rest = vec![0; 304];
+        futures::AsyncReadExt::read_exact(&mut stream, &mut rest)
+            .await
+            .unwrap();
+        assert_eq!(&rest[..4], &300u32.to_le_bytes());
+        assert_eq!(&rest[4..], &messages[2][..]);
+    }
+
+    #[tokio::test]
+    async fn take_buf_read() {
+        let messages = [vec![1; 100], vec![2; 200], vec![3; 300]];
+        // Whole frames parsed out of the stream's buffer, and frames straddling it
+        for capacity in [1024, 64] {
+            let mut stream = RingBuffer::new(1024);
+            for message in messages.iter() {
+                let mut frame = (message.len() as u32).to_le_bytes().to_vec();
+                frame.extend(message);
+                stream.write_all(&frame).await.unwrap();
+            }
+            let stream = futures::io::BufReader::with_capacity(capacity, stream);
+            let mut take = MessageSink::from_buf_read(stream).take(2);
+            assert_eq!(messages[0], take.next().await.unwrap().unwrap());
+            assert_eq!(messages[1], take.next().await.unwrap().unwrap());
+            assert!(take.next().await.is_none());
+            let (mut stream, buffer) = take.into_inner();
+            assert!(buffer.is_empty());
+            let mut rest = vec![0; 304];
+            futures::AsyncReadExt::read_exact(&mut stream, &mut rest)
+                .await
+                .unwrap();
+            assert_eq!(&rest[..4], &300u32.to_le_bytes());
+            assert_eq!(&rest[4..], &messages[2][..]);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use futures::Future;
+use std::{pin::Pin, task::Context, time::Duration};
+
+type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A source of sleeps, so that timeouts work with whichever runtime drives the sink.
+/// Implemented for any function returning a sleep future, e.g. `tokio::time::sleep`.
+pub trait Timer: Send + Sync {
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+impl<F, S> Timer for F
+where
+    F: Fn(Duration) -> S + Send + Sync,
+    S: Future<Output = ()> + Send + 'static,
+{
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(self(duration))
+    }
+}
+
+/// Fires once a given duration passes without progress, e.g. a read that stalls partway
+/// through a frame.
+pub(crate) struct IdleTimer {
+    timer: Box<dyn Timer>,
+    duration: Duration,
+    sleep: Option<Sleep>,
+}
+
+impl IdleTimer {
+    pub(crate) fn new(duration: Duration, timer: impl Timer + 'static) -> Self {
+        Self {
+            timer: Box::new(timer),
+            duration,
+            sleep: None,
+        }
+    }
+
+    /// Called whenever work is left pending, returning true once it has stalled. The timer
+    /// only runs while `active` and restarts whenever `progress` is made.
+    pub(crate) fn expired(&mut self, cx: &mut Context<'_>, active: bool, progress: bool) -> bool {
+        if !active || progress {
+            self.sleep = None;
+        }
+        if !active {
+            return false;
+        }
+        let sleep = match &mut self.sleep {
+            Some(sleep) => sleep,
+            None => self.sleep.insert(self.timer.sleep(self.duration)),
+        };
+        if sleep.as_mut().poll(cx).is_pending() {
+            return false;
+        }
+        self.sleep = None;
+        true
+    }
+}
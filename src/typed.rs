@@ -0,0 +1,234 @@
+use crate::{MessageSink, SinkError};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready, Sink, Stream,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    error::Error,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Converts values to and from message payloads for a [`TypedSink`].
+pub trait Encoding<T> {
+    type Error: Error + Send + Sync + 'static;
+    fn encode(&self, value: &T) -> Result<Vec<u8>, Self::Error>;
+    fn decode(&self, bytes: &[u8]) -> Result<T, Self::Error>;
+}
+
+/// Encodes values with `bincode`'s default configuration.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl<T> Encoding<T> for Bincode
+where
+    T: Serialize + DeserializeOwned,
+{
+    type Error = bincode::Error;
+    fn encode(&self, value: &T) -> Result<Vec<u8>, Self::Error> {
+        bincode::serialize(value)
+    }
+    fn decode(&self, bytes: &[u8]) -> Result<T, Self::Error> {
+        bincode::deserialize(bytes)
+    }
+}
+
+/// Encodes values as UTF-8 JSON with `serde_json`.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl<T> Encoding<T> for Json
+where
+    T: Serialize + DeserializeOwned,
+{
+    type Error = serde_json::Error;
+    fn encode(&self, value: &T) -> Result<Vec<u8>, Self::Error> {
+        serde_json::to_vec(value)
+    }
+    fn decode(&self, bytes: &[u8]) -> Result<T, Self::Error> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+/// A [`TypedSink`] carrying one JSON value per message, created with
+/// `JsonSink::with_encoding(sink, Json)`.
+#[cfg(feature = "json")]
+pub type JsonSink<S, T> = TypedSink<S, T, Json>;
+
+/// A [`MessageSink`] that sends and receives values of type `T` rather than raw bytes.
+/// Values that fail to encode or decode surface as `SinkError::Codec` without closing the
+/// sink.
+pub struct TypedSink<S, T, E = Bincode>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    sink: MessageSink<S>,
+    encoding: E,
+    value: PhantomData<fn(T) -> T>,
+}
+
+impl<S, T> TypedSink<S, T>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: Serialize + DeserializeOwned,
+{
+    pub fn new(sink: MessageSink<S>) -> Self {
+        Self::with_encoding(sink, Bincode)
+    }
+}
+
+impl<S, T, E> TypedSink<S, T, E>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    E: Encoding<T>,
+{
+    pub fn with_encoding(sink: MessageSink<S>, encoding: E) -> Self {
+        Self {
+            sink,
+            encoding,
+            value: PhantomData,
+        }
+    }
+    /// Encode `value` and queue it to be written, see [`MessageSink::write`]
+    pub fn write(&mut self, value: &T) -> Result<usize, SinkError> {
+        let message = self.encoding.encode(value).map_err(SinkError::codec)?;
+        self.sink.write(message)
+    }
+    /// Receive and decode the next value, see [`MessageSink::recv`]
+    pub async fn recv(&mut self) -> Result<Option<T>, SinkError> {
+        match self.sink.recv().await? {
+            Some(message) => self.decode(&message).map(Some),
+            None => Ok(None),
+        }
+    }
+    pub fn get_ref(&self) -> &MessageSink<S> {
+        &self.sink
+    }
+    pub fn get_mut(&mut self) -> &mut MessageSink<S> {
+        &mut self.sink
+    }
+    pub fn into_inner(self) -> MessageSink<S> {
+        self.sink
+    }
+    fn decode(&self, message: &[u8]) -> Result<T, SinkError> {
+        self.encoding.decode(message).map_err(SinkError::codec)
+    }
+}
+
+impl<S, T, E> Stream for TypedSink<S, T, E>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    E: Encoding<T> + Unpin,
+{
+    type Item = Result<T, SinkError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let typed = self.get_mut();
+        let message = ready!(Pin::new(&mut typed.sink).poll_next(cx));
+        Poll::Ready(message.map(|message| typed.decode(&message?)))
+    }
+}
+
+impl<S, T, E> Sink<T> for TypedSink<S, T, E>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    E: Encoding<T> + Unpin,
+{
+    type Error = SinkError;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Vec<u8>>::poll_ready(Pin::new(&mut self.get_mut().sink), cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.get_mut().write(&item).map(drop)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Vec<u8>>::poll_flush(Pin::new(&mut self.get_mut().sink), cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Vec<u8>>::poll_close(Pin::new(&mut self.get_mut().sink), cx)
+    }
+}
+
+#[cfg(test)]
+mod typed_test {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use futures_ringbuf::RingBuffer;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        value: f64,
+        tags: Vec<u16>,
+        ok: bool,
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let reading = Reading {
+            sensor: "north".to_string(),
+            value: 21.5,
+            tags: vec![1, 2, 3],
+            ok: true,
+        };
+        let sink = MessageSink::new(RingBuffer::new(1024));
+        let mut typed = TypedSink::new(sink);
+        typed.write(&reading).unwrap();
+        assert_eq!(Some(reading.clone()), typed.recv().await.unwrap());
+        typed.send(reading.clone()).await.unwrap();
+        assert_eq!(reading, typed.next().await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn decode_error() {
+        let mut sink = MessageSink::new(RingBuffer::new(1024));
+        sink.write(vec![1]).unwrap();
+        let mut typed: TypedSink<_, Reading> = TypedSink::new(sink);
+        match typed.recv().await {
+            Err(SinkError::Codec(_)) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+        assert!(!typed.get_ref().is_closed());
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn json() {
+        let reading = Reading {
+            sensor: "south".to_string(),
+            value: -3.25,
+            tags: vec![7],
+            ok: false,
+        };
+        let sink = MessageSink::new(RingBuffer::new(1024));
+        let mut json = JsonSink::with_encoding(sink, Json);
+        json.write(&reading).unwrap();
+        json.get_mut().write(b"{\"sensor\": 1}".to_vec()).unwrap();
+        json.get_mut().write(b"[1, 2]".to_vec()).unwrap();
+        assert_eq!(Some(reading), json.recv().await.unwrap());
+        for _ in 0..2 {
+            match json.recv().await {
+                Err(SinkError::Codec(e)) => assert!(e.is::<serde_json::Error>()),
+                Err(e) => panic!("unexpected error {}", e),
+                Ok(_) => panic!("unexpected success"),
+            }
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn json_payload() {
+        let sink = MessageSink::new(RingBuffer::new(1024));
+        let mut json = JsonSink::with_encoding(sink, Json);
+        json.write(&serde_json::json!({ "id": 7, "name": "seven" }))
+            .unwrap();
+        let mut sink = json.into_inner();
+        let payload = sink.recv().await.unwrap().unwrap();
+        assert_eq!(payload, br#"{"id":7,"name":"seven"}"#);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{codec::Codec, writer::FrameWriter, FrameFormat, SinkError};
+use futures::{future::poll_fn, io::AsyncWrite, ready, Sink};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Frames messages onto a stream that is only ever written to, e.g. a log shipper's
+/// connection. Framing, the outbound buffer and backpressure work as they do for a
+/// [`MessageSink`](crate::MessageSink), without any read buffer or an `AsyncRead` bound.
+pub struct WriteOnlySink<W, C = FrameFormat>
+where
+    W: AsyncWrite + Unpin,
+{
+    stream: W,
+    writer: FrameWriter<C>,
+    closed: bool,
+}
+
+impl<W> WriteOnlySink<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(stream: W) -> Self {
+        Self::with_codec(stream, FrameFormat::default())
+    }
+}
+
+impl<W, C> WriteOnlySink<W, C>
+where
+    W: AsyncWrite + Unpin,
+    C: Codec,
+{
+    pub fn with_codec(stream: W, codec: C) -> Self {
+        Self {
+            stream,
+            writer: FrameWriter::new(codec),
+            closed: false,
+        }
+    }
+    /// See [`MessageSink::high_water_mark`](crate::MessageSink::high_water_mark)
+    pub fn high_water_mark(&mut self, bytes: usize) {
+        self.writer.high_water = bytes;
+    }
+    /// See [`MessageSink::write_limit`](crate::MessageSink::write_limit)
+    pub fn write_limit(&mut self, bytes: usize) {
+        self.writer.limit = bytes;
+    }
+    /// See [`MessageSink::write`](crate::MessageSink::write). Nothing is written until the
+    /// sink is flushed or polled through `Sink`.
+    pub fn write(&mut self, message: Vec<u8>) -> Result<usize, SinkError> {
+        self.check_open()?;
+        self.writer.write(message)
+    }
+    /// See [`MessageSink::write_slice`](crate::MessageSink::write_slice)
+    pub fn write_slice(&mut self, message: &[u8]) -> Result<usize, SinkError> {
+        self.check_open()?;
+        self.writer.write_slice(message)
+    }
+    /// Write queued messages to the stream and flush it
+    pub async fn flush(&mut self) -> Result<(), SinkError> {
+        poll_fn(|cx| Sink::<Vec<u8>>::poll_flush(Pin::new(&mut *self), cx)).await
+    }
+    /// Number of bytes queued but not yet written
+    pub fn buffered(&self) -> usize {
+        self.writer.buffer.len()
+    }
+    pub fn get_ref(&self) -> &W {
+        &self.stream
+    }
+    /// The stream, dropping anything still queued
+    pub fn into_inner(self) -> W {
+        self.stream
+    }
+    fn check_open(&self) -> Result<(), SinkError> {
+        match self.closed {
+            true => Err(SinkError::Closed),
+            false => Ok(()),
+        }
+    }
+}
+
+impl<W, C> Sink<Vec<u8>> for WriteOnlySink<W, C>
+where
+    W: AsyncWrite + Unpin,
+    C: Codec,
+{
+    type Error = SinkError;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let sink = self.get_mut();
+        sink.check_open()?;
+        sink.writer.poll_ready(&mut sink.stream, cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.get_mut().write(item).map(drop)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let sink = self.get_mut();
+        sink.check_open()?;
+        ready!(sink.writer.poll_flush(&mut sink.stream, cx))?;
+        Pin::new(&mut sink.stream)
+            .poll_flush(cx)
+            .map_err(SinkError::Write)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let sink = self.get_mut();
+        if sink.closed {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(sink.writer.poll_flush(&mut sink.stream, cx))?;
+        ready!(Pin::new(&mut sink.stream).poll_close(cx)).map_err(SinkError::Write)?;
+        sink.closed = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod write_only_test {
+    use super::*;
+    use crate::{frames, HeaderWidth, ParseError};
+    use futures::SinkExt;
+
+    #[tokio::test]
+    async fn write_only() {
+        let messages = vec![vec![1; 16], Vec::new(), vec![2; 300]];
+        let mut sink = WriteOnlySink::new(Vec::new());
+        for message in &messages[..2] {
+            sink.write(message.clone()).unwrap();
+        }
+        assert_eq!(sink.buffered(), 24);
+        assert!(sink.get_ref().is_empty());
+        sink.send(messages[2].clone()).await.unwrap();
+        assert_eq!(sink.buffered(), 0);
+        sink.close().await.unwrap();
+        assert!(matches!(sink.write(Vec::new()), Err(SinkError::Closed)));
+        let mut wire = sink.into_inner();
+        let parsed: Vec<_> = frames(&mut wire)
+            .map(|frame| frame.unwrap().into_message())
+            .collect();
+        assert_eq!(parsed, messages);
+        assert!(wire.is_empty());
+    }
+
+    #[tokio::test]
+    async fn codec() {
+        let format = FrameFormat {
+            width: HeaderWidth::U8,
+            ..Default::default()
+        };
+        let mut sink = WriteOnlySink::with_codec(Vec::new(), format);
+        sink.write_slice(b"log line").unwrap();
+        match sink.write(vec![0; 256]) {
+            Err(SinkError::Parse(ParseError::Corrupt)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        sink.flush().await.unwrap();
+        assert_eq!(sink.into_inner(), b"\x08log line");
+    }
+}
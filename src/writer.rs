@@ -0,0 +1,319 @@
+use crate::{
+    ack::{self, Acker, WriteAck},
+    async_buffer::AsyncBuffer,
+    codec::Codec,
+    frame::FrameFormat,
+    timer::IdleTimer,
+    SinkError,
+};
+use futures::{io::AsyncWrite, ready};
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Progress of closing the write side of the stream on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WriteHalf {
+    Open,
+    Closing,
+    Closed,
+}
+
+/// Outbound half of the framing state: framed bytes waiting to be written to the stream.
+pub(crate) struct FrameWriter<C = FrameFormat> {
+    pub(crate) buffer: AsyncBuffer,
+    pub(crate) format: C,
+    pub(crate) high_water: usize,
+    pub(crate) limit: usize,
+    /// Hold writes back until this many bytes are queued, see `MessageSink::coalesce`
+    pub(crate) coalesce: Option<usize>,
+    /// Longest message the peer agreed to accept
+    pub(crate) max_message: usize,
+    pub(crate) bytes_written: u64,
+    pub(crate) messages_written: u64,
+    /// Sends a ping once nothing has been queued for a while
+    pub(crate) keepalive: Option<IdleTimer>,
+    pub(crate) half: WriteHalf,
+    /// Total bytes ever queued at the end of each message that is not fully written yet
+    boundaries: VecDeque<u64>,
+    /// Acks waiting on the total bytes written to reach the end of their message
+    pub(crate) acks: VecDeque<(u64, Acker)>,
+    queued: u64,
+    /// Value of `queued` when the keepalive was last checked
+    idle_since: u64,
+    /// Value of `queued` after the last ping, which is written without coalescing
+    ping_end: u64,
+}
+
+impl<C: Default> Default for FrameWriter<C> {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<C> FrameWriter<C> {
+    pub(crate) fn new(format: C) -> Self {
+        Self {
+            buffer: Default::default(),
+            format,
+            high_water: 64 * 1024,
+            limit: usize::MAX,
+            coalesce: None,
+            max_message: usize::MAX,
+            bytes_written: 0,
+            messages_written: 0,
+            keepalive: None,
+            half: WriteHalf::Open,
+            boundaries: Default::default(),
+            acks: Default::default(),
+            queued: 0,
+            idle_since: 0,
+            ping_end: 0,
+        }
+    }
+}
+
+impl<C: Codec> FrameWriter<C> {
+    pub(crate) fn write(&mut self, message: Vec<u8>) -> Result<usize, SinkError> {
+        self.write_all([message])
+    }
+
+    /// Like `write`, returning an ack that resolves once the whole frame has been written.
+    pub(crate) fn write_acked(&mut self, message: Vec<u8>) -> Result<WriteAck, SinkError> {
+        self.write(message)?;
+        let (acker, ack) = ack::ack();
+        self.acks.push_back((self.queued, acker));
+        Ok(ack)
+    }
+
+    /// Frame every message and queue them together, or none of them if any fails to
+    /// encode or they would not fit under the write limit. Each message body is queued
+    /// as-is rather than copied in behind its header. Resolves to the number of framed
+    /// bytes queued.
+    pub(crate) fn write_all<I>(&mut self, messages: I) -> Result<usize, SinkError>
+    where
+        I: IntoIterator,
+        I::Item: Into<Vec<u8>>,
+    {
+        self.write_frames(messages.into_iter().map(|message| (0, message.into())))
+    }
+
+    /// Like `write_all`, but each message is sent on the channel it is paired with.
+    pub(crate) fn write_frames<I>(&mut self, frames: I) -> Result<usize, SinkError>
+    where
+        I: IntoIterator<Item = (u16, Vec<u8>)>,
+    {
+        if self.half != WriteHalf::Open {
+            return Err(SinkError::Closed);
+        }
+        let mut encoded = Vec::new();
+        let mut length = 0;
+        for (channel, message) in frames {
+            if message.len() > self.max_message {
+                return Err(SinkError::LimitExceeded);
+            }
+            let (header, body, trailer) = self.format.frame(channel, message)?;
+            length += header.len() + body.len() + trailer.len();
+            encoded.push((header, body, trailer, length as u64));
+        }
+        if self.buffer.len() + length > self.limit {
+            return Err(SinkError::WriteBufferFull);
+        }
+        self.buffer.reserve(length);
+        for (header, body, trailer, end) in encoded {
+            self.buffer.extend(header);
+            self.buffer.extend(body);
+            self.buffer.extend(trailer);
+            self.boundaries.push_back(self.queued + end);
+        }
+        self.queued += length as u64;
+        Ok(length)
+    }
+
+    /// Like `write`, but copies the body straight out of `message` into the buffer.
+    pub(crate) fn write_slice(&mut self, message: &[u8]) -> Result<usize, SinkError> {
+        if self.half != WriteHalf::Open {
+            return Err(SinkError::Closed);
+        }
+        if message.len() > self.max_message {
+            return Err(SinkError::LimitExceeded);
+        }
+        let header = self.format.channel_header(0, message)?;
+        let trailer = self.format.trailer(message);
+        let length = header.len() + message.len() + trailer.len();
+        if self.buffer.len() + length > self.limit {
+            return Err(SinkError::WriteBufferFull);
+        }
+        self.buffer.reserve(length);
+        self.buffer.extend(header);
+        self.buffer.extend_from_slice(message);
+        self.buffer.extend(trailer);
+        self.queued += length as u64;
+        self.boundaries.push_back(self.queued);
+        Ok(length)
+    }
+
+    /// Queue bytes that are already framed, subject to the same write limit as messages.
+    pub(crate) fn write_raw(&mut self, framed: Vec<u8>) -> Result<(), SinkError> {
+        if self.half != WriteHalf::Open {
+            return Err(SinkError::Closed);
+        }
+        if self.buffer.len() + framed.len() > self.limit {
+            return Err(SinkError::WriteBufferFull);
+        }
+        self.queued += framed.len() as u64;
+        self.buffer.extend(framed);
+        Ok(())
+    }
+
+    /// Queue a ping if nothing else has been queued for the keepalive interval.
+    pub(crate) fn poll_keepalive(&mut self, cx: &mut Context<'_>) {
+        let Some(keepalive) = &mut self.keepalive else {
+            return;
+        };
+        if self.half != WriteHalf::Open {
+            return;
+        }
+        let progress = self.queued != self.idle_since;
+        if keepalive.expired(cx, true, progress) {
+            if let Some(ping) = self.format.ping() {
+                self.queued += ping.len() as u64;
+                self.ping_end = self.queued;
+                self.buffer.extend(ping);
+            }
+            // Start timing the next interval straight away
+            keepalive.expired(cx, true, true);
+        }
+        self.idle_since = self.queued;
+    }
+
+    /// Whether writing should wait until more is queued or the sink is flushed
+    pub(crate) fn deferred(&self) -> bool {
+        let pinged = self.bytes_written < self.ping_end;
+        matches!(self.coalesce, Some(threshold) if self.buffer.len() < threshold && !pinged)
+    }
+
+    /// Drop everything queued but not yet written, e.g. when the stream is replaced, and
+    /// reopen the write half.
+    pub(crate) fn reset(&mut self) {
+        self.buffer.clear();
+        self.boundaries.clear();
+        self.acks.clear();
+        self.queued = self.bytes_written;
+        self.idle_since = self.queued;
+        self.ping_end = self.queued;
+        self.half = WriteHalf::Open;
+    }
+
+    /// Make a single attempt to write buffered bytes to `stream`.
+    pub(crate) fn poll_write<W>(
+        &mut self,
+        stream: &mut W,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), SinkError>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if self.buffer.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        // Streams without vectored write support fall back to writing the first slice
+        let slices = self.buffer.io_slices();
+        let result = loop {
+            match Pin::new(&mut *stream).poll_write_vectored(cx, &slices) {
+                Poll::Ready(Err(e)) if e.kind() == ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+        match result {
+            // Retrying would spin forever on a stream that can't take any more
+            Poll::Ready(Ok(0)) => Poll::Ready(Err(SinkError::write(ErrorKind::WriteZero.into()))),
+            Poll::Ready(Ok(length)) => {
+                self.buffer.consume(length);
+                self.bytes_written += length as u64;
+                while let Some(&end) = self.boundaries.front() {
+                    if end > self.bytes_written {
+                        break;
+                    }
+                    self.boundaries.pop_front();
+                    self.messages_written += 1;
+                }
+                while let Some((end, _)) = self.acks.front() {
+                    if *end > self.bytes_written {
+                        break;
+                    }
+                    if let Some((_, acker)) = self.acks.pop_front() {
+                        acker.written();
+                    }
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) if e.kind() == ErrorKind::WouldBlock => {
+                // The stream may not have registered the task, so ask to be polled again
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(SinkError::write(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Write buffered bytes to `stream` until the buffer is empty or the stream is not ready
+    /// to accept more, retrying straight away after a partial write.
+    pub(crate) fn poll_flush<W>(
+        &mut self,
+        stream: &mut W,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), SinkError>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        while !self.buffer.is_empty() {
+            ready!(self.poll_write(stream, cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Once the write half is closing, flush what is buffered and close the stream for
+    /// writing.
+    pub(crate) fn poll_shutdown<W>(
+        &mut self,
+        stream: &mut W,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), SinkError>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if self.half != WriteHalf::Closing {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.poll_flush(stream, cx))?;
+        ready!(Pin::new(&mut *stream).poll_close(cx)).map_err(SinkError::write)?;
+        self.half = WriteHalf::Closed;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Resolves once the buffer has drained below the high-water mark (or the write limit,
+    /// if that is lower).
+    pub(crate) fn poll_ready<W>(
+        &mut self,
+        stream: &mut W,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), SinkError>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mark = self.high_water.min(self.limit);
+        if self.buffer.len() < mark {
+            return Poll::Ready(Ok(()));
+        }
+        match self.poll_flush(stream, cx) {
+            Poll::Pending if self.buffer.len() >= mark => Poll::Pending,
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
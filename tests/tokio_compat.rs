@@ -0,0 +1,58 @@
+use futures::{AsyncReadExt, AsyncWriteExt};
+use futures_ringbuf::RingBuffer;
+use message_sink::{Frame, FrameFormat, MessageSink, MessageSinkBuilder};
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{Decoder, Encoder, LengthDelimitedCodec},
+};
+
+#[tokio::test]
+async fn read_length_delimited() {
+    let messages = [b"hello".to_vec(), Vec::new(), vec![7; 300]];
+    let mut codec = LengthDelimitedCodec::new();
+    let mut encoded = BytesMut::new();
+    for message in messages.iter() {
+        codec
+            .encode(Bytes::from(message.clone()), &mut encoded)
+            .unwrap();
+    }
+    let mut stream = RingBuffer::new(1024);
+    stream.write_all(&encoded).await.unwrap();
+    stream.close().await.unwrap();
+    let mut sink = MessageSinkBuilder::new().tokio_compat().build(stream);
+    for message in messages {
+        assert_eq!(Some(message), sink.recv().await.unwrap());
+    }
+    assert_eq!(None, sink.recv().await.unwrap());
+}
+
+#[tokio::test]
+async fn write_length_delimited() {
+    let messages = [b"hello".to_vec(), Vec::new(), vec![7; 300]];
+    let mut sink = MessageSink::new(RingBuffer::new(1024));
+    sink.tokio_compat();
+    sink.write_all(messages.to_vec()).unwrap();
+    sink.flush().await.unwrap();
+    let (mut stream, _) = sink.into_inner();
+    stream.close().await.unwrap();
+    let mut encoded = Vec::new();
+    stream.read_to_end(&mut encoded).await.unwrap();
+    let mut encoded = BytesMut::from(&encoded[..]);
+    let mut codec = LengthDelimitedCodec::new();
+    for message in messages {
+        let frame = codec.decode(&mut encoded).unwrap().unwrap();
+        assert_eq!(frame, message);
+    }
+    assert!(encoded.is_empty());
+}
+
+#[test]
+fn frame_format() {
+    let mut encoded = BytesMut::new();
+    LengthDelimitedCodec::new()
+        .encode(Bytes::from_static(b"frame"), &mut encoded)
+        .unwrap();
+    let format = FrameFormat::tokio_compat();
+    let frame = Frame::new(b"frame".to_vec()).encode(&format).unwrap();
+    assert_eq!(frame, encoded);
+}